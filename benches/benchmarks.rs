@@ -69,7 +69,7 @@ fn world_with_bvh(n: u32) -> (World, Schedule, Schedule) {
         algorithms::Bvh::default(),
     ));
 
-    prepare_schedule.add_systems(prepare_spatial_lookup);
+    prepare_schedule.add_systems(prepare_spatial_lookup::<DefaultSpatialIndex, GlobalTransform>);
     query_schedule.add_systems(system_with_spatial_query);
 
     (world, prepare_schedule, query_schedule)
@@ -84,7 +84,7 @@ fn world_with_naive(n: u32) -> (World, Schedule, Schedule) {
         algorithms::Naive::default(),
     ));
 
-    prepare_schedule.add_systems(prepare_spatial_lookup);
+    prepare_schedule.add_systems(prepare_spatial_lookup::<DefaultSpatialIndex, GlobalTransform>);
     query_schedule.add_systems(system_with_spatial_query);
 
     (world, prepare_schedule, query_schedule)
@@ -1,10 +1,19 @@
 //! Bounding Volume Hierarchy -accelerated spatial lookup
 
-use crate::SpatialLookupAlgorithm;
+use super::geometry::{
+    self, AabbQuery, ConeQuery, CylinderQuery, FrustumQuery, ObbQuery, OverlapShape, QueryShape,
+    SegmentQuery, ShellQuery, SphereQuery,
+};
+use super::nearest::{Candidate, CandidateQueue, Closest, KNearest, NearestIter};
+use crate::{SpatialLayers, SpatialLookupAlgorithm, UniqueEntities};
+use bevy::camera::primitives::Frustum;
+use bevy::ecs::entity::EntityHashMap;
 use bevy::math::{FloatOrd, FloatPow};
 use bevy::prelude::*;
 use bevy::tasks::TaskPool;
 use log::warn;
+use std::any::Any;
+use std::collections::BinaryHeap;
 
 type EntityPositionPair = (Entity, Vec3);
 
@@ -34,8 +43,13 @@ pub struct Bvh {
     /// tree structure but makes tree generation slower.
     pub max_split_samples_per_axis: usize,
     root: Option<BvhNode>,
+    /// Whether `prepare` has been called. The root is `None` before that, or if there were no
+    /// entities to prepare with.
+    prepared: bool,
     tree_depth: usize,
     task_pool: TaskPool,
+    /// Bounding sphere radius of every entity which isn't a point.
+    extents: EntityHashMap<f32>,
 }
 
 impl Default for Bvh {
@@ -44,14 +58,26 @@ impl Default for Bvh {
             entities_per_leaf: 10_000,
             max_split_samples_per_axis: 10,
             root: None,
+            prepared: false,
             tree_depth: 0,
             task_pool: TaskPool::new(),
+            extents: EntityHashMap::default(),
         }
     }
 }
 
 impl SpatialLookupAlgorithm for Bvh {
     fn prepare(&mut self, entities: &[EntityPositionPair]) {
+        self.extents.clear();
+        self.prepared = true;
+
+        // An index can be prepared before any entity is added to it
+        if entities.is_empty() {
+            self.root = None;
+            self.tree_depth = 0;
+            return;
+        }
+
         let root = split_node(
             entities,
             self.entities_per_leaf,
@@ -63,16 +89,435 @@ impl SpatialLookupAlgorithm for Bvh {
         self.root = Some(root);
     }
 
+    fn prepare_with_extents(&mut self, entities: &[EntityPositionPair], extents: &[f32]) {
+        self.prepare(entities);
+
+        for ((entity, _), extent) in entities.iter().zip(extents) {
+            if *extent > 0.0 {
+                self.extents.insert(*entity, *extent);
+            }
+        }
+
+        if let Some(root) = &mut self.root
+            && !self.extents.is_empty()
+        {
+            root.grow_by_extents(&self.extents);
+        }
+    }
+
+    fn prepare_with_layers(
+        &mut self,
+        entities: &[EntityPositionPair],
+        extents: &[f32],
+        layers: &[SpatialLayers],
+    ) {
+        self.prepare_with_extents(entities, extents);
+
+        // the tree is built with every entity on the default layer
+        let layers: EntityHashMap<SpatialLayers> = entities
+            .iter()
+            .zip(layers)
+            .filter(|(_, layers)| **layers != SpatialLayers::DEFAULT)
+            .map(|((entity, _), layers)| (*entity, *layers))
+            .collect();
+
+        if let Some(root) = &mut self.root
+            && !layers.is_empty()
+        {
+            root.assign_layers(&layers);
+        }
+    }
+
     fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut found = Vec::new();
+        self.visit_in_radius(sample_point, radius, &mut |entity, _| found.push(entity));
+
+        found
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        self.visit_in_radius_on_layers(sample_point, radius, SpatialLayers::ALL, visit);
+    }
+
+    fn visit_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        if let Some(root) = &self.root {
+            root.visit_in_radius(sample_point, radius, mask, visit);
+        } else if !self.prepared {
+            warn!(
+                "called Bvh::visit_in_radius before initializing the lookup with Bvh::prepare,\
+                no entities will be returned"
+            );
+        }
+    }
+
+    fn count_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.root.as_ref().map_or(0, |root| {
+            root.count_in_shape(&SphereQuery {
+                center: sample_point,
+                radius,
+            })
+        })
+    }
+
+    fn estimate_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.root.as_ref().map_or(0, |root| {
+            root.estimate_in_radius(sample_point, radius).round() as usize
+        })
+    }
+
+    fn entities_in_radius_with_distance(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> Vec<(Entity, Vec3, f32)> {
+        let sphere = SphereQuery {
+            center: sample_point,
+            radius,
+        };
+
+        let mut found = Vec::new();
+        self.visit_shape(
+            "entities_in_radius_with_distance",
+            &sphere,
+            SpatialLayers::ALL,
+            &mut |entity, position| {
+                found.push((entity, position, position.distance_squared(sample_point)));
+            },
+        );
+
+        found
+    }
+
+    fn entities_in_aabb_on_layers(&self, min: Vec3, max: Vec3, mask: SpatialLayers) -> Vec<Entity> {
+        self.entities_in_shape("entities_in_aabb", &AabbQuery { min, max }, mask)
+    }
+
+    fn entities_along_ray_on_layers(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        let segment = SegmentQuery {
+            origin,
+            direction: *direction,
+            max_t,
+            thickness,
+        };
+        if segment.is_empty() {
+            return Vec::new();
+        }
+
+        let mut found = Vec::new();
+        self.visit_shape(
+            "entities_along_ray",
+            &segment,
+            mask,
+            &mut |entity, position| {
+                if let Some(t) = segment.hit(position) {
+                    found.push((entity, t));
+                }
+            },
+        );
+        found.sort_by_key(|(entity, t)| (FloatOrd(*t), *entity));
+
+        found
+    }
+
+    fn entities_in_frustum_on_layers(&self, frustum: &Frustum, mask: SpatialLayers) -> Vec<Entity> {
+        self.entities_in_shape("entities_in_frustum", &FrustumQuery { frustum }, mask)
+    }
+
+    fn entities_in_cone_on_layers(
+        &self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let cone = ConeQuery {
+            apex,
+            direction: *direction,
+            half_angle,
+            range,
+        };
+
+        self.entities_in_shape("entities_in_cone", &cone, mask)
+    }
+
+    fn entities_in_capsule_on_layers(
+        &self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let capsule = SegmentQuery::between(a, b, radius);
+        if capsule.is_empty() {
+            return Vec::new();
+        }
+
+        self.entities_in_shape("entities_in_capsule", &capsule, mask)
+    }
+
+    fn entities_in_obb_on_layers(
+        &self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let obb = ObbQuery {
+            center,
+            half_extents,
+            rotation,
+        };
+
+        self.entities_in_shape("entities_in_obb", &obb, mask)
+    }
+
+    fn entities_in_cylinder_on_layers(
+        &self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let cylinder = CylinderQuery {
+            center,
+            axis: *axis,
+            radius,
+            height_range,
+        };
+        if cylinder.is_empty() {
+            return Vec::new();
+        }
+
+        self.entities_in_shape("entities_in_cylinder", &cylinder, mask)
+    }
+
+    fn entities_in_shell_on_layers(
+        &self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let shell = ShellQuery {
+            center,
+            min_radius,
+            max_radius,
+        };
+
+        self.entities_in_shape("entities_in_shell", &shell, mask)
+    }
+
+    fn entities_overlapping_sphere_on_layers(
+        &self,
+        center: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let mut found = Vec::new();
+        let sphere = SphereQuery { center, radius };
+        self.visit_overlapping(
+            "entities_overlapping_sphere",
+            &sphere,
+            mask,
+            &mut |entity| found.push(entity),
+        );
+
+        found
+    }
+
+    fn entities_overlapping_aabb_on_layers(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let mut found = Vec::new();
+        let aabb = AabbQuery { min, max };
+        self.visit_overlapping("entities_overlapping_aabb", &aabb, mask, &mut |entity| {
+            found.push(entity)
+        });
+
+        found
+    }
+
+    fn visit_pairs_within_on_layers(
+        &self,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
         if let Some(root) = &self.root {
-            root.entities_in_radius(sample_point, radius)
-        } else {
+            root.visit_pairs_within(distance, mask, visit);
+        } else if !self.prepared {
             warn!(
-                "called Bvh::entities_in_radius before initializing the lookup with Bvh::prepare,\
+                "called Bvh::visit_pairs_within before initializing the lookup with Bvh::prepare,\
+                no pairs will be returned"
+            );
+        }
+    }
+
+    fn visit_pairs_between_on_layers(
+        &self,
+        other: &dyn SpatialLookupAlgorithm,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        let Some(other) = other.as_any().and_then(|other| other.downcast_ref::<Bvh>()) else {
+            crate::visit_pairs_between_by_lookup(self, other, distance, mask, visit);
+            return;
+        };
+
+        if let (Some(root), Some(other_root)) = (&self.root, &other.root)
+            && distance >= 0.0
+        {
+            root.visit_pairs_between(other_root, distance, mask, visit);
+        }
+    }
+
+    fn nearest_k_on_layers(
+        &self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Option<Entity>,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        let Some(root) = &self.root else {
+            if !self.prepared {
+                warn!(
+                    "called Bvh::nearest_k before initializing the lookup with Bvh::prepare,\
+                    no entities will be returned"
+                );
+            }
+            return Vec::new();
+        };
+
+        let mut nearest = KNearest::new(sample_point, k, max_distance, exclude);
+
+        // Best-first traversal: always enter the node closest to the sample point next, and stop
+        // once the closest remaining node is further away than the current k-th nearest entity.
+        // Nodes without entities on the mask are never entered, so they can't take any places.
+        let mut queue = BinaryHeap::new();
+        if root.layers.matches(mask) {
+            queue.push(Closest {
+                distance_squared: root.distance_squared(sample_point),
+                item: root,
+            });
+        }
+
+        while let Some(Closest {
+            distance_squared,
+            item: node,
+        }) = queue.pop()
+        {
+            if distance_squared > nearest.bound_squared() {
+                break;
+            }
+
+            match &node.kind {
+                BvhNodeKind::Leaf(entity_position_pairs, layers) => {
+                    for (entity, position) in on_layers(entity_position_pairs, layers, mask) {
+                        nearest.offer(*entity, *position);
+                    }
+                }
+                BvhNodeKind::Branch(left, right) => {
+                    for child in [left, right] {
+                        if child.layers.matches(mask) {
+                            queue.push(Closest {
+                                distance_squared: child.distance_squared(sample_point),
+                                item: child,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        nearest.into_sorted_vec()
+    }
+
+    fn nearest_iter_on_layers(
+        &self,
+        sample_point: Vec3,
+        mask: SpatialLayers,
+    ) -> Box<dyn Iterator<Item = (Entity, f32)> + '_> {
+        let mut queue = CandidateQueue::new();
+
+        if let Some(root) = &self.root {
+            if root.layers.matches(mask) {
+                queue.push(Closest {
+                    distance_squared: root.distance_squared(sample_point),
+                    item: Candidate::Node(root),
+                });
+            }
+        } else if !self.prepared {
+            warn!(
+                "called Bvh::nearest_iter before initializing the lookup with Bvh::prepare,\
                 no entities will be returned"
             );
-            Vec::new()
         }
+
+        Box::new(NearestIter::new(
+            queue,
+            move |node: &BvhNode, queue: &mut CandidateQueue<&BvhNode>| match &node.kind {
+                BvhNodeKind::Leaf(entity_position_pairs, layers) => {
+                    queue.extend(on_layers(entity_position_pairs, layers, mask).map(
+                        |(entity, position)| Closest {
+                            distance_squared: position.distance_squared(sample_point),
+                            item: Candidate::Entity(*entity),
+                        },
+                    ));
+                }
+                BvhNodeKind::Branch(left, right) => {
+                    for child in [left, right] {
+                        if child.layers.matches(mask) {
+                            queue.push(Closest {
+                                distance_squared: child.distance_squared(sample_point),
+                                item: Candidate::Node(child),
+                            });
+                        }
+                    }
+                }
+            },
+        ))
+    }
+
+    fn supports_layers(&self) -> bool {
+        true
+    }
+
+    fn reports_positions(&self) -> bool {
+        true
+    }
+
+    fn returns_unique_entities(&self) -> Option<UniqueEntities> {
+        // SAFETY: every lookup of `Bvh` visits each entity at most once.
+        Some(unsafe { UniqueEntities::new() })
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
     }
 
     fn debug_gizmos(&self, gizmos: &mut Gizmos) {
@@ -82,6 +527,62 @@ impl SpatialLookupAlgorithm for Bvh {
     }
 }
 
+impl Bvh {
+    /// Returns a list of all entities inside `shape` whose layers match `mask`.
+    fn entities_in_shape(
+        &self,
+        method: &str,
+        shape: &impl QueryShape,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let mut found = Vec::new();
+        self.visit_shape(method, shape, mask, &mut |entity, _| found.push(entity));
+
+        found
+    }
+
+    /// Calls `visit` for every entity inside `shape` whose layers match `mask`.
+    ///
+    /// `method` is only used to warn about lookups done before the tree has been built.
+    fn visit_shape(
+        &self,
+        method: &str,
+        shape: &impl QueryShape,
+        mask: SpatialLayers,
+        visit: &mut impl FnMut(Entity, Vec3),
+    ) {
+        if let Some(root) = &self.root {
+            root.visit_shape(shape, mask, visit);
+        } else if !self.prepared {
+            warn!(
+                "called Bvh::{method} before initializing the lookup with Bvh::prepare,\
+                no entities will be returned"
+            );
+        }
+    }
+
+    /// Calls `visit` for every entity whose bounding sphere intersects `shape` and whose layers
+    /// match `mask`.
+    ///
+    /// `method` is only used to warn about lookups done before the tree has been built.
+    fn visit_overlapping(
+        &self,
+        method: &str,
+        shape: &impl OverlapShape,
+        mask: SpatialLayers,
+        visit: &mut impl FnMut(Entity),
+    ) {
+        if let Some(root) = &self.root {
+            root.visit_overlapping(shape, &self.extents, mask, visit);
+        } else if !self.prepared {
+            warn!(
+                "called Bvh::{method} before initializing the lookup with Bvh::prepare,\
+                no entities will be returned"
+            );
+        }
+    }
+}
+
 /// Recursively splits a slice of Entity, Position pairs into BVH nodes.
 ///
 /// This implementation uses the Surface Area Heuristic with a user-controllable amount of
@@ -99,9 +600,12 @@ fn split_node(
     let aabb = calculate_aabb(&entities);
 
     if entities.len() <= entities_per_leaf {
+        let layers = vec![SpatialLayers::DEFAULT; entities.len()];
         return BvhNode {
             aabb,
-            kind: BvhNodeKind::Leaf(entities),
+            max_extent: 0.0,
+            layers: SpatialLayers::DEFAULT,
+            kind: BvhNodeKind::Leaf(entities, layers),
         };
     }
 
@@ -153,6 +657,8 @@ fn split_node(
 
     BvhNode {
         aabb,
+        max_extent: 0.0,
+        layers: SpatialLayers::DEFAULT,
         kind: BvhNodeKind::Branch(Box::new(left_node), Box::new(right_node)),
     }
 }
@@ -233,10 +739,24 @@ impl Aabb {
 
 #[derive(Debug, Clone)]
 enum BvhNodeKind {
-    Leaf(Vec<EntityPositionPair>),
+    /// The entities of the leaf, and the layers of each of them.
+    Leaf(Vec<EntityPositionPair>, Vec<SpatialLayers>),
     Branch(Box<BvhNode>, Box<BvhNode>),
 }
 
+/// Iterates over the entities of a leaf whose layers match `mask`.
+fn on_layers<'a>(
+    entity_position_pairs: &'a [EntityPositionPair],
+    layers: &'a [SpatialLayers],
+    mask: SpatialLayers,
+) -> impl Iterator<Item = &'a EntityPositionPair> {
+    entity_position_pairs
+        .iter()
+        .zip(layers)
+        .filter(move |(_, layers)| layers.matches(mask))
+        .map(|(pair, _)| pair)
+}
+
 /// Node of the BVH tree.
 ///
 /// Each node contains an AABB (the chosen bounding volume),
@@ -244,37 +764,347 @@ enum BvhNodeKind {
 #[derive(Debug, Clone)]
 struct BvhNode {
     aabb: Aabb,
+    /// Largest bounding sphere radius of the entities under this node. The AABB grown by this
+    /// bounds the extents of all of them.
+    max_extent: f32,
+    /// Union of the layers of the entities under this node, so nodes without any entity on the
+    /// mask of a lookup can be skipped.
+    layers: SpatialLayers,
     kind: BvhNodeKind,
 }
 
 impl BvhNode {
-    /// Returns a list of entities that are in radius of the given sample point.
-    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
-        if !self.intersects_sphere(sample_point, radius) {
-            return Vec::new();
+    /// Calls `visit` for every entity that is in radius of the given sample point and whose
+    /// layers match `mask`.
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        if !self.layers.matches(mask) || !self.intersects_sphere(sample_point, radius) {
+            return;
         }
 
         match &self.kind {
-            BvhNodeKind::Leaf(entity_position_pairs) => entity_position_pairs
-                .iter()
-                .filter_map(|(entity, position)| {
+            BvhNodeKind::Leaf(entity_position_pairs, layers) => {
+                for (entity, position) in on_layers(entity_position_pairs, layers, mask) {
                     if position.distance(sample_point) <= radius {
-                        Some(*entity)
+                        visit(*entity, *position);
+                    }
+                }
+            }
+            BvhNodeKind::Branch(left, right) => {
+                left.visit_in_radius(sample_point, radius, mask, visit);
+                right.visit_in_radius(sample_point, radius, mask, visit);
+            }
+        }
+    }
+
+    /// Calls `visit` for every entity under this node which is inside `shape` and whose layers
+    /// match `mask`.
+    ///
+    /// Nodes which lie completely inside the shape are visited without testing each position.
+    fn visit_shape(
+        &self,
+        shape: &impl QueryShape,
+        mask: SpatialLayers,
+        visit: &mut impl FnMut(Entity, Vec3),
+    ) {
+        if !self.layers.matches(mask) || !shape.intersects_aabb(self.aabb.min, self.aabb.max) {
+            return;
+        }
+
+        if shape.contains_aabb(self.aabb.min, self.aabb.max) {
+            self.visit_all(mask, visit);
+            return;
+        }
+
+        match &self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, layers) => {
+                for (entity, position) in on_layers(entity_position_pairs, layers, mask) {
+                    if shape.contains_point(*position) {
+                        visit(*entity, *position);
+                    }
+                }
+            }
+            BvhNodeKind::Branch(left, right) => {
+                left.visit_shape(shape, mask, visit);
+                right.visit_shape(shape, mask, visit);
+            }
+        }
+    }
+
+    /// Returns the number of entities inside `shape`.
+    ///
+    /// Nodes which lie completely inside the shape are counted without testing each entity.
+    fn count_in_shape(&self, shape: &impl QueryShape) -> usize {
+        if !shape.intersects_aabb(self.aabb.min, self.aabb.max) {
+            return 0;
+        }
+
+        if shape.contains_aabb(self.aabb.min, self.aabb.max) {
+            return self.len();
+        }
+
+        match &self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, _) => entity_position_pairs
+                .iter()
+                .filter(|(_, position)| shape.contains_point(*position))
+                .count(),
+            BvhNodeKind::Branch(left, right) => {
+                left.count_in_shape(shape) + right.count_in_shape(shape)
+            }
+        }
+    }
+
+    /// Estimates the number of entities in radius of the given sample point.
+    ///
+    /// Leaves which are only partially covered by the sphere contribute their size scaled by how
+    /// much of their AABB is covered by the sphere's bounding box.
+    fn estimate_in_radius(&self, sample_point: Vec3, radius: f32) -> f32 {
+        let sphere = SphereQuery {
+            center: sample_point,
+            radius,
+        };
+
+        if !sphere.intersects_aabb(self.aabb.min, self.aabb.max) {
+            return 0.;
+        }
+
+        if sphere.contains_aabb(self.aabb.min, self.aabb.max) {
+            return self.len() as f32;
+        }
+
+        match &self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, _) => {
+                let covered = geometry::aabb_sphere_overlap_fraction(
+                    self.aabb.min,
+                    self.aabb.max,
+                    sample_point,
+                    radius,
+                );
+
+                entity_position_pairs.len() as f32 * covered
+            }
+            BvhNodeKind::Branch(left, right) => {
+                left.estimate_in_radius(sample_point, radius)
+                    + right.estimate_in_radius(sample_point, radius)
+            }
+        }
+    }
+
+    /// Calls `visit` for every pair of entities under this node which are within `distance` of
+    /// each other and whose layers match `mask`.
+    fn visit_pairs_within(
+        &self,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        if !self.layers.matches(mask) {
+            return;
+        }
+
+        match &self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, layers) => {
+                for (i, (a, a_position)) in entity_position_pairs.iter().enumerate() {
+                    if !layers[i].matches(mask) {
+                        continue;
+                    }
+
+                    let (rest, rest_layers) = (&entity_position_pairs[i + 1..], &layers[i + 1..]);
+                    for (b, b_position) in on_layers(rest, rest_layers, mask) {
+                        if a_position.distance(*b_position) <= distance {
+                            visit(*a, *b);
+                        }
+                    }
+                }
+            }
+            BvhNodeKind::Branch(left, right) => {
+                left.visit_pairs_within(distance, mask, visit);
+                right.visit_pairs_within(distance, mask, visit);
+                left.visit_pairs_between(right, distance, mask, visit);
+            }
+        }
+    }
+
+    /// Calls `visit` for every pair of one entity under this node and one under `other` which are
+    /// within `distance` of each other and whose layers match `mask`.
+    ///
+    /// Both nodes are descended together, always splitting the larger one, so that node pairs
+    /// further apart than `distance` are pruned as early as possible.
+    fn visit_pairs_between(
+        &self,
+        other: &BvhNode,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        if !self.layers.matches(mask) || !other.layers.matches(mask) {
+            return;
+        }
+
+        let gap_squared = geometry::aabb_aabb_distance_squared(
+            self.aabb.min,
+            self.aabb.max,
+            other.aabb.min,
+            other.aabb.max,
+        );
+        if gap_squared > distance.squared() {
+            return;
+        }
+
+        match (&self.kind, &other.kind) {
+            (
+                BvhNodeKind::Leaf(entity_position_pairs, layers),
+                BvhNodeKind::Leaf(other_pairs, other_layers),
+            ) => {
+                for (a, a_position) in on_layers(entity_position_pairs, layers, mask) {
+                    for (b, b_position) in on_layers(other_pairs, other_layers, mask) {
+                        if a_position.distance(*b_position) <= distance {
+                            visit(*a, *b);
+                        }
+                    }
+                }
+            }
+            (BvhNodeKind::Branch(left, right), BvhNodeKind::Leaf(..)) => {
+                left.visit_pairs_between(other, distance, mask, visit);
+                right.visit_pairs_between(other, distance, mask, visit);
+            }
+            (BvhNodeKind::Leaf(..), BvhNodeKind::Branch(left, right)) => {
+                self.visit_pairs_between(left, distance, mask, visit);
+                self.visit_pairs_between(right, distance, mask, visit);
+            }
+            (BvhNodeKind::Branch(left, right), BvhNodeKind::Branch(other_left, other_right)) => {
+                if self.volume() >= other.volume() {
+                    left.visit_pairs_between(other, distance, mask, visit);
+                    right.visit_pairs_between(other, distance, mask, visit);
+                } else {
+                    self.visit_pairs_between(other_left, distance, mask, visit);
+                    self.visit_pairs_between(other_right, distance, mask, visit);
+                }
+            }
+        }
+    }
+
+    /// Returns the volume of this node's AABB.
+    fn volume(&self) -> f32 {
+        (self.aabb.max - self.aabb.min).element_product()
+    }
+
+    /// Sets `max_extent` of this node and all nodes below it from the given extents.
+    fn grow_by_extents(&mut self, extents: &EntityHashMap<f32>) {
+        self.max_extent = match &mut self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, _) => entity_position_pairs
+                .iter()
+                .filter_map(|(entity, _)| extents.get(entity).copied())
+                .fold(0.0, f32::max),
+            BvhNodeKind::Branch(left, right) => {
+                left.grow_by_extents(extents);
+                right.grow_by_extents(extents);
+                left.max_extent.max(right.max_extent)
+            }
+        };
+    }
+
+    /// Sets the layers of every entity under this node from the given layers, leaving entities
+    /// missing from them on the default layer, and updates the union of every node.
+    fn assign_layers(&mut self, layers: &EntityHashMap<SpatialLayers>) {
+        self.layers = match &mut self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, leaf_layers) => {
+                for ((entity, _), entity_layers) in
+                    entity_position_pairs.iter().zip(leaf_layers.iter_mut())
+                {
+                    *entity_layers = layers.get(entity).copied().unwrap_or_default();
+                }
+
+                SpatialLayers(leaf_layers.iter().fold(0, |union, layers| union | layers.0))
+            }
+            BvhNodeKind::Branch(left, right) => {
+                left.assign_layers(layers);
+                right.assign_layers(layers);
+                SpatialLayers(left.layers.0 | right.layers.0)
+            }
+        };
+    }
+
+    /// Calls `visit` for every entity under this node whose bounding sphere intersects `shape` and
+    /// whose layers match `mask`.
+    fn visit_overlapping(
+        &self,
+        shape: &impl OverlapShape,
+        extents: &EntityHashMap<f32>,
+        mask: SpatialLayers,
+        visit: &mut impl FnMut(Entity),
+    ) {
+        let min = self.aabb.min - self.max_extent;
+        let max = self.aabb.max + self.max_extent;
+        if !self.layers.matches(mask) || !shape.intersects_aabb(min, max) {
+            return;
+        }
+
+        // every entity position is inside the shape, so every extent overlaps it
+        if shape.contains_aabb(self.aabb.min, self.aabb.max) {
+            self.visit_all(mask, &mut |entity, _| visit(entity));
+            return;
+        }
+
+        match &self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, layers) => {
+                for (entity, position) in on_layers(entity_position_pairs, layers, mask) {
+                    let extent = if self.max_extent > 0.0 {
+                        extents.get(entity).copied().unwrap_or_default()
                     } else {
-                        None
+                        0.0
+                    };
+
+                    if shape.intersects_sphere(*position, extent) {
+                        visit(*entity);
                     }
-                })
-                .collect(),
+                }
+            }
             BvhNodeKind::Branch(left, right) => {
-                let mut total = left.entities_in_radius(sample_point, radius);
+                left.visit_overlapping(shape, extents, mask, visit);
+                right.visit_overlapping(shape, extents, mask, visit);
+            }
+        }
+    }
 
-                total.extend(right.entities_in_radius(sample_point, radius));
+    /// Returns the number of entities stored under this node.
+    fn len(&self) -> usize {
+        match &self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, _) => entity_position_pairs.len(),
+            BvhNodeKind::Branch(left, right) => left.len() + right.len(),
+        }
+    }
 
-                total
+    /// Calls `visit` for every entity stored under this node whose layers match `mask`.
+    fn visit_all(&self, mask: SpatialLayers, visit: &mut impl FnMut(Entity, Vec3)) {
+        if !self.layers.matches(mask) {
+            return;
+        }
+
+        match &self.kind {
+            BvhNodeKind::Leaf(entity_position_pairs, layers) => {
+                for (entity, position) in on_layers(entity_position_pairs, layers, mask) {
+                    visit(*entity, *position);
+                }
+            }
+            BvhNodeKind::Branch(left, right) => {
+                left.visit_all(mask, visit);
+                right.visit_all(mask, visit);
             }
         }
     }
 
+    /// Returns the squared distance from the sample point to this node's AABB.
+    #[inline]
+    fn distance_squared(&self, sample_point: Vec3) -> f32 {
+        geometry::aabb_distance_squared(self.aabb.min, self.aabb.max, sample_point)
+    }
+
     /// Returns true if this node intersects given sphere.
     #[inline]
     fn intersects_sphere(&self, sample_point: Vec3, radius: f32) -> bool {
@@ -295,7 +1125,7 @@ impl BvhNode {
 
     fn count_depth(&self) -> usize {
         match &self.kind {
-            BvhNodeKind::Leaf(_) => 1,
+            BvhNodeKind::Leaf(..) => 1,
             BvhNodeKind::Branch(left, right) => 1 + left.count_depth().max(right.count_depth()),
         }
     }
@@ -309,7 +1139,7 @@ impl BvhNode {
         );
 
         match &self.kind {
-            BvhNodeKind::Leaf(_) => {
+            BvhNodeKind::Leaf(..) => {
                 gizmos.cube(
                     Transform::from_translation(cuboid_centroid).with_scale(cuboid_scale),
                     Color::hsv((level as f32) / (max_depth as f32) * 360., 0.8, 1.0),
@@ -0,0 +1,537 @@
+//! Geometry helpers shared by the built-in lookup algorithms.
+//!
+//! Per-entity tests live here so that all algorithms agree on exactly which entities match a
+//! query, including points lying exactly on the boundary of the queried shape.
+
+use bevy::camera::primitives::Frustum;
+use bevy::prelude::*;
+
+/// Returns true if `point` is inside the box `min..=max`, inclusive.
+#[inline]
+pub(crate) fn aabb_contains_point(min: Vec3, max: Vec3, point: Vec3) -> bool {
+    point.cmpge(min).all() && point.cmple(max).all()
+}
+
+/// Returns true if the boxes `a_min..=a_max` and `b_min..=b_max` overlap or touch.
+#[inline]
+pub(crate) fn aabb_intersects_aabb(a_min: Vec3, a_max: Vec3, b_min: Vec3, b_max: Vec3) -> bool {
+    a_min.cmple(b_max).all() && a_max.cmpge(b_min).all()
+}
+
+/// Returns true if the box `inner_min..=inner_max` lies completely inside `outer_min..=outer_max`.
+#[inline]
+pub(crate) fn aabb_contains_aabb(
+    outer_min: Vec3,
+    outer_max: Vec3,
+    inner_min: Vec3,
+    inner_max: Vec3,
+) -> bool {
+    inner_min.cmpge(outer_min).all() && inner_max.cmple(outer_max).all()
+}
+
+/// Returns the 8 corners of the box `min..=max`.
+#[inline]
+pub(crate) fn aabb_corners(min: Vec3, max: Vec3) -> [Vec3; 8] {
+    std::array::from_fn(|corner| {
+        Vec3::select(
+            BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+            max,
+            min,
+        )
+    })
+}
+
+/// Returns the squared distance from `point` to the closest point of the box `min..=max`.
+///
+/// Points inside the box have a distance of zero.
+#[inline]
+pub(crate) fn aabb_distance_squared(min: Vec3, max: Vec3, point: Vec3) -> f32 {
+    (min - point)
+        .max(point - max)
+        .max(Vec3::ZERO)
+        .length_squared()
+}
+
+/// Returns true if the segment `origin + direction * t` for `t` in `min_t..=max_t` touches the
+/// box `min..=max`.
+///
+/// Based on the slab test; `min_t` and `max_t` may be infinite to test rays and lines.
+pub(crate) fn segment_intersects_aabb(
+    origin: Vec3,
+    direction: Vec3,
+    (min_t, max_t): (f32, f32),
+    min: Vec3,
+    max: Vec3,
+) -> bool {
+    let mut t_enter = min_t;
+    let mut t_exit = max_t;
+
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            // parallel to the slab, so the origin has to be inside it
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return false;
+            }
+            continue;
+        }
+
+        let inv_direction = direction[axis].recip();
+        let t0 = (min[axis] - origin[axis]) * inv_direction;
+        let t1 = (max[axis] - origin[axis]) * inv_direction;
+
+        t_enter = t_enter.max(t0.min(t1));
+        t_exit = t_exit.min(t0.max(t1));
+
+        if t_enter > t_exit {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Returns the squared distance between the closest points of two boxes, or 0 if they intersect.
+#[inline]
+pub(crate) fn aabb_aabb_distance_squared(
+    a_min: Vec3,
+    a_max: Vec3,
+    b_min: Vec3,
+    b_max: Vec3,
+) -> f32 {
+    (a_min - b_max)
+        .max(b_min - a_max)
+        .max(Vec3::ZERO)
+        .length_squared()
+}
+
+/// Returns the squared distance from `point` to the furthest point of the box `min..=max`.
+#[inline]
+pub(crate) fn aabb_max_distance_squared(min: Vec3, max: Vec3, point: Vec3) -> f32 {
+    (point - min)
+        .abs()
+        .max((point - max).abs())
+        .length_squared()
+}
+
+/// Estimates the fraction of the volume of `min..=max` which lies inside the given sphere.
+///
+/// The estimate is the part of the box covered by the sphere's bounding cube, scaled by the
+/// share of that cube taken up by the sphere. Flat boxes have no volume, so only their overlap
+/// along their non-flat axes is considered.
+#[inline]
+pub(crate) fn aabb_sphere_overlap_fraction(min: Vec3, max: Vec3, center: Vec3, radius: f32) -> f32 {
+    let (other_min, other_max) = (center - radius, center + radius);
+    if !aabb_intersects_aabb(min, max, other_min, other_max) {
+        return 0.0;
+    }
+
+    let size = max - min;
+    let overlap = max.min(other_max) - min.max(other_min);
+
+    let mut fraction = 1.0;
+    for axis in 0..3 {
+        if size[axis] > 0.0 {
+            fraction *= overlap[axis] / size[axis];
+        }
+    }
+
+    // a sphere fills pi/6 of its bounding cube
+    fraction * std::f32::consts::FRAC_PI_6
+}
+
+/// A query shape which the tree algorithms can use to prune their nodes.
+pub(crate) trait QueryShape {
+    /// Returns true if the shape contains the given point.
+    ///
+    /// This is the exact per-entity test, and decides which entities a query returns.
+    fn contains_point(&self, point: Vec3) -> bool;
+
+    /// Returns true if the shape may contain points of the box `min..=max`.
+    ///
+    /// False positives are allowed, but false negatives are not.
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool;
+
+    /// Returns true if every point of the box `min..=max` is inside the shape.
+    ///
+    /// False negatives are allowed, but false positives are not.
+    fn contains_aabb(&self, _min: Vec3, _max: Vec3) -> bool {
+        false
+    }
+}
+
+/// Query for points within `radius` of `center`, inclusive.
+pub(crate) struct SphereQuery {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl QueryShape for SphereQuery {
+    fn contains_point(&self, point: Vec3) -> bool {
+        point.distance(self.center) <= self.radius
+    }
+
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        aabb_distance_squared(min, max, self.center) <= self.radius * self.radius
+    }
+
+    fn contains_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        aabb_max_distance_squared(min, max, self.center) <= self.radius * self.radius
+    }
+}
+
+/// Axis-aligned box query.
+pub(crate) struct AabbQuery {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl QueryShape for AabbQuery {
+    fn contains_point(&self, point: Vec3) -> bool {
+        aabb_contains_point(self.min, self.max, point)
+    }
+
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        aabb_intersects_aabb(self.min, self.max, min, max)
+    }
+
+    fn contains_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        aabb_contains_aabb(self.min, self.max, min, max)
+    }
+}
+
+/// A query shape which can also be tested against entities with a bounding sphere.
+///
+/// Node pruning and the `contains_aabb` shortcut still use the `QueryShape` tests, against node
+/// bounds grown by the largest extent below them.
+pub(crate) trait OverlapShape: QueryShape {
+    /// Returns true if the shape intersects the sphere at `center` with the given `radius`.
+    ///
+    /// For a radius of zero this *MUST* agree with `contains_point`.
+    fn intersects_sphere(&self, center: Vec3, radius: f32) -> bool;
+}
+
+impl OverlapShape for SphereQuery {
+    fn intersects_sphere(&self, center: Vec3, radius: f32) -> bool {
+        center.distance(self.center) <= self.radius + radius
+    }
+}
+
+impl OverlapShape for AabbQuery {
+    fn intersects_sphere(&self, center: Vec3, radius: f32) -> bool {
+        aabb_distance_squared(self.min, self.max, center) <= radius * radius
+    }
+}
+
+/// Query for points within `thickness` of the segment `origin + direction * t`, `t` in
+/// `0..=max_t`.
+pub(crate) struct SegmentQuery {
+    pub origin: Vec3,
+    /// Unit direction of the segment.
+    pub direction: Vec3,
+    pub max_t: f32,
+    pub thickness: f32,
+}
+
+impl SegmentQuery {
+    /// Segment from `a` to `b`, i.e. a capsule with the given radius.
+    ///
+    /// If `a` and `b` are the same point, this is a sphere around it.
+    pub fn between(a: Vec3, b: Vec3, radius: f32) -> Self {
+        Self {
+            origin: a,
+            direction: (b - a).normalize_or_zero(),
+            max_t: a.distance(b),
+            thickness: radius,
+        }
+    }
+
+    /// Returns true if the segment can't contain any points, because its length or thickness is
+    /// negative or NaN.
+    ///
+    /// Callers check this up front, as `hit` and the node tests don't reject such segments.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        !(self.max_t >= 0.0 && self.thickness >= 0.0)
+    }
+
+    /// Returns the segment parameter of the point closest to `point`, if `point` is within
+    /// `thickness` of the segment.
+    #[inline]
+    pub fn hit(&self, point: Vec3) -> Option<f32> {
+        // not `clamp`, which panics for a negative or NaN `max_t`
+        let t = (point - self.origin)
+            .dot(self.direction)
+            .max(0.0)
+            .min(self.max_t);
+        let closest = self.origin + self.direction * t;
+
+        (point.distance_squared(closest) <= self.thickness * self.thickness).then_some(t)
+    }
+}
+
+impl QueryShape for SegmentQuery {
+    fn contains_point(&self, point: Vec3) -> bool {
+        self.hit(point).is_some()
+    }
+
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        // the box grown by `thickness` contains every point within `thickness` of it
+        let grow = Vec3::splat(self.thickness);
+        segment_intersects_aabb(
+            self.origin,
+            self.direction,
+            (0.0, self.max_t),
+            min - grow,
+            max + grow,
+        )
+    }
+}
+
+/// Query for points inside a camera frustum.
+///
+/// Points exactly on one of the frustum planes count as inside.
+pub(crate) struct FrustumQuery<'a> {
+    pub frustum: &'a Frustum,
+}
+
+impl FrustumQuery<'_> {
+    /// Signed distances of the box `min..=max` to a half-space: `(center, radius)` such that the
+    /// box lies between `center - radius` and `center + radius` along the plane normal.
+    #[inline]
+    fn box_extent(min: Vec3, max: Vec3, normal_d: Vec4) -> (f32, f32) {
+        let center = (min + max) * 0.5;
+        let half_extents = (max - min) * 0.5;
+        let normal = normal_d.truncate();
+
+        (
+            normal.dot(center) + normal_d.w,
+            half_extents.dot(normal.abs()),
+        )
+    }
+}
+
+impl QueryShape for FrustumQuery<'_> {
+    fn contains_point(&self, point: Vec3) -> bool {
+        let point = point.extend(1.0);
+        self.frustum
+            .half_spaces
+            .iter()
+            .all(|half_space| half_space.normal_d().dot(point) >= 0.0)
+    }
+
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        self.frustum.half_spaces.iter().all(|half_space| {
+            let (center, radius) = Self::box_extent(min, max, half_space.normal_d());
+            center + radius >= 0.0
+        })
+    }
+
+    fn contains_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        self.frustum.half_spaces.iter().all(|half_space| {
+            let (center, radius) = Self::box_extent(min, max, half_space.normal_d());
+            center - radius >= 0.0
+        })
+    }
+}
+
+/// Query for points inside a cone with its apex at `apex`, opening towards `direction`.
+///
+/// Follows the same inclusive rules as the radius lookups: points exactly `range` away from the
+/// apex, or exactly `half_angle` away from the direction, are inside. The apex itself is inside.
+pub(crate) struct ConeQuery {
+    pub apex: Vec3,
+    /// Unit direction of the cone axis.
+    pub direction: Vec3,
+    /// Angle between the axis and the surface of the cone, in radians.
+    pub half_angle: f32,
+    pub range: f32,
+}
+
+impl QueryShape for ConeQuery {
+    fn contains_point(&self, point: Vec3) -> bool {
+        let offset = point - self.apex;
+        let distance = offset.length();
+
+        distance <= self.range
+            && (distance == 0.0 || offset.dot(self.direction) >= distance * self.half_angle.cos())
+    }
+
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        if aabb_distance_squared(min, max, self.apex) > self.range * self.range {
+            return false;
+        }
+
+        // Test the cone against the bounding sphere of the box.
+        let center = (min + max) * 0.5;
+        let radius = (max - min).length() * 0.5;
+        let offset = center - self.apex;
+        let distance = offset.length();
+
+        if distance <= radius {
+            return true;
+        }
+
+        let angle_to_center = (offset.dot(self.direction) / distance)
+            .clamp(-1.0, 1.0)
+            .acos();
+        let angular_radius = (radius / distance).asin();
+
+        // small margin so rounding never rejects a node containing a point on the cone surface
+        angle_to_center - angular_radius <= self.half_angle + 1e-4
+    }
+
+    fn contains_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        // The cone is only convex up to a half angle of 90 degrees, after which the corners being
+        // inside no longer means the whole box is.
+        if self.half_angle > std::f32::consts::FRAC_PI_2 {
+            return false;
+        }
+
+        aabb_corners(min, max)
+            .into_iter()
+            .all(|corner| self.contains_point(corner))
+    }
+}
+
+/// Query for points inside an oriented box.
+///
+/// Points exactly on a face of the box are inside.
+pub(crate) struct ObbQuery {
+    pub center: Vec3,
+    pub half_extents: Vec3,
+    pub rotation: Quat,
+}
+
+impl QueryShape for ObbQuery {
+    fn contains_point(&self, point: Vec3) -> bool {
+        let local = self.rotation.inverse() * (point - self.center);
+        local.abs().cmple(self.half_extents).all()
+    }
+
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        // Separating axis test between the two boxes: the face normals of both boxes, and the
+        // cross products of their edges.
+        let aabb_center = (min + max) * 0.5;
+        let aabb_half_extents = (max - min) * 0.5;
+        let obb_axes = [
+            self.rotation * Vec3::X,
+            self.rotation * Vec3::Y,
+            self.rotation * Vec3::Z,
+        ];
+        let offset = self.center - aabb_center;
+
+        let separated_along = |axis: Vec3| {
+            let aabb_radius = aabb_half_extents.dot(axis.abs());
+            let obb_radius = self.half_extents.x * obb_axes[0].dot(axis).abs()
+                + self.half_extents.y * obb_axes[1].dot(axis).abs()
+                + self.half_extents.z * obb_axes[2].dot(axis).abs();
+
+            offset.dot(axis).abs() > aabb_radius + obb_radius
+        };
+
+        for aabb_axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            if separated_along(aabb_axis) {
+                return false;
+            }
+
+            for obb_axis in obb_axes {
+                let axis = aabb_axis.cross(obb_axis);
+                // parallel edges don't give a separating axis; the face normals cover them
+                if axis.length_squared() > 1e-6 && separated_along(axis) {
+                    return false;
+                }
+            }
+        }
+
+        !obb_axes.into_iter().any(separated_along)
+    }
+
+    fn contains_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        aabb_corners(min, max)
+            .into_iter()
+            .all(|corner| self.contains_point(corner))
+    }
+}
+
+/// Query for points within `radius` of the line through `center` along `axis`, optionally
+/// limited to a range of heights along the axis.
+pub(crate) struct CylinderQuery {
+    pub center: Vec3,
+    /// Unit direction of the cylinder axis.
+    pub axis: Vec3,
+    pub radius: f32,
+    /// Inclusive range of `point.dot(axis)`, e.g. world-space Y for a vertical axis.
+    pub height_range: Option<(f32, f32)>,
+}
+
+impl CylinderQuery {
+    /// Returns true if the cylinder can't contain any points, because its radius is negative or
+    /// NaN.
+    ///
+    /// Callers check this up front, as `intersects_aabb` shrinks the node boxes for a negative
+    /// radius while `contains_point` squares it.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.radius.is_nan() || self.radius < 0.0
+    }
+}
+
+impl QueryShape for CylinderQuery {
+    fn contains_point(&self, point: Vec3) -> bool {
+        let offset = point - self.center;
+        let radial = offset - self.axis * offset.dot(self.axis);
+
+        radial.length_squared() <= self.radius * self.radius
+            && self.height_range.is_none_or(|(low, high)| {
+                let height = point.dot(self.axis);
+                low <= height && height <= high
+            })
+    }
+
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        // The cylinder axis, limited to the height range, has to pass through the box grown by
+        // the radius.
+        let center_height = self.center.dot(self.axis);
+        let t_range = self
+            .height_range
+            .map_or((f32::NEG_INFINITY, f32::INFINITY), |(low, high)| {
+                (low - center_height, high - center_height)
+            });
+        let grow = Vec3::splat(self.radius);
+
+        segment_intersects_aabb(self.center, self.axis, t_range, min - grow, max + grow)
+    }
+
+    fn contains_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        aabb_corners(min, max)
+            .into_iter()
+            .all(|corner| self.contains_point(corner))
+    }
+}
+
+/// Query for points between two spheres around the same center.
+///
+/// Both radii are inclusive, like in the radius lookups.
+pub(crate) struct ShellQuery {
+    pub center: Vec3,
+    pub min_radius: f32,
+    pub max_radius: f32,
+}
+
+impl QueryShape for ShellQuery {
+    fn contains_point(&self, point: Vec3) -> bool {
+        let distance = point.distance(self.center);
+        self.min_radius <= distance && distance <= self.max_radius
+    }
+
+    fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        // reject boxes outside the outer sphere, and boxes completely inside the inner sphere
+        aabb_distance_squared(min, max, self.center) <= self.max_radius * self.max_radius
+            && aabb_max_distance_squared(min, max, self.center) >= self.min_radius * self.min_radius
+    }
+
+    fn contains_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        aabb_max_distance_squared(min, max, self.center) <= self.max_radius * self.max_radius
+            && aabb_distance_squared(min, max, self.center) >= self.min_radius * self.min_radius
+    }
+}
@@ -3,7 +3,9 @@
 //! You can implement your own algorithm by implementing the `SpatialLookupAlgorithm` trait.
 
 mod bvh;
+pub(crate) mod geometry;
 mod naive;
+pub(crate) mod nearest;
 mod octree;
 
 // Re-export algorithms for ease of use.
@@ -18,8 +20,10 @@ pub use octree::OctreeConfig;
 /// TODO: Consider using a fixture-based test framework
 #[cfg(test)]
 mod tests {
-    use crate::{SpatialLookupState, algorithms};
+    use crate::{SpatialLayers, SpatialLookupAlgorithm, SpatialLookupState, algorithms};
+    use bevy::camera::primitives::Frustum;
     use bevy::prelude::*;
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
     use turborand::SeededCore;
     use turborand::prelude::*;
 
@@ -45,6 +49,91 @@ mod tests {
         entities
     }
 
+    /// Algorithm which only implements the required methods, to test the default implementations
+    /// of all other lookups.
+    #[derive(Default)]
+    struct RequiredOnly(algorithms::Naive);
+
+    impl SpatialLookupAlgorithm for RequiredOnly {
+        fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+            self.0.prepare(entities);
+        }
+
+        fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+            self.0.entities_in_radius(sample_point, radius)
+        }
+    }
+
+    /// Helper function to make a prepared lookup state for every built-in algorithm, and for the
+    /// default implementations of the lookups
+    fn prepared_lookup_states(n: u32) -> Vec<(&'static str, SpatialLookupState)> {
+        lookup_states_with(world_with_n_entities(n))
+    }
+
+    /// Same as `prepared_lookup_states`, but with the given entities
+    fn lookup_states_with(
+        entities: Vec<(Entity, Vec3)>,
+    ) -> Vec<(&'static str, SpatialLookupState)> {
+        let mut small_leaf_bvh = algorithms::Bvh::default();
+        small_leaf_bvh.entities_per_leaf = 1_000;
+
+        let mut states = vec![
+            (
+                "Naive",
+                SpatialLookupState::with_algorithm(algorithms::Naive::default()),
+            ),
+            (
+                "Bvh",
+                SpatialLookupState::with_algorithm(algorithms::Bvh::default()),
+            ),
+            (
+                "Bvh (small leaves)",
+                SpatialLookupState::with_algorithm(small_leaf_bvh),
+            ),
+            (
+                "Octree",
+                SpatialLookupState::with_algorithm(algorithms::Octree::default()),
+            ),
+            (
+                "Default methods",
+                SpatialLookupState::with_algorithm(RequiredOnly::default()),
+            ),
+        ];
+
+        for (_, lookup_state) in &mut states {
+            lookup_state.entities = entities.clone();
+            lookup_state.prepare_algorithm();
+        }
+
+        states
+    }
+
+    /// Helper function to assert that every algorithm returns the same set of entities.
+    ///
+    /// Returns the number of entities found.
+    fn assert_all_algorithms_agree(
+        n: u32,
+        lookup: impl Fn(&SpatialLookupState) -> Vec<Entity>,
+    ) -> usize {
+        let mut expected: Option<Vec<Entity>> = None;
+
+        for (name, lookup_state) in prepared_lookup_states(n) {
+            let mut found = lookup(&lookup_state);
+            found.sort();
+
+            let len = found.len();
+            found.dedup();
+            assert_eq!(found.len(), len, "{name} returned duplicate entities");
+
+            match &expected {
+                Some(expected) => assert_eq!(&found, expected, "{name} disagrees with Naive"),
+                None => expected = Some(found),
+            }
+        }
+
+        expected.map_or(0, |expected| expected.len())
+    }
+
     #[test]
     fn test_bvh_in_range() {
         let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
@@ -66,4 +155,669 @@ mod tests {
 
         assert_eq!(found.len(), 39);
     }
+
+    #[test]
+    fn test_all_empty() {
+        for (name, lookup_state) in prepared_lookup_states(0) {
+            assert!(
+                lookup_state.entities_in_radius(Vec3::ZERO, 1.0).is_empty(),
+                "{name} found entities in an empty index"
+            );
+            assert!(
+                lookup_state.nearest_k(Vec3::ZERO, 1, 1.0, None).is_empty(),
+                "{name} found neighbours in an empty index"
+            );
+        }
+    }
+
+    #[test]
+    fn test_all_in_aabb() {
+        let half = Vec3::new(2.0, 1.0, 3.0);
+
+        let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+            lookup_state.entities_in_aabb(-half, half)
+        });
+        assert_eq!(found, 583);
+
+        let found = assert_all_algorithms_agree(10_000, |lookup_state| {
+            lookup_state.entities_in_aabb(Vec3::splat(-WORLD_SIZE), Vec3::splat(WORLD_SIZE))
+        });
+        assert_eq!(found, 10_000);
+
+        let found = assert_all_algorithms_agree(10_000, |lookup_state| {
+            lookup_state
+                .entities_in_aabb(Vec3::splat(WORLD_SIZE + 1.0), Vec3::splat(WORLD_SIZE + 2.0))
+        });
+        assert_eq!(found, 0);
+    }
+
+    #[test]
+    fn test_all_nearest_k() {
+        let states = prepared_lookup_states(100_000);
+        let (_, naive) = &states[0];
+        let expected = naive.nearest_k(Vec3::ZERO, 10, LOOKUP_RADIUS, None);
+        let closest = expected[0].0;
+
+        assert_eq!(expected.len(), 10);
+        assert!(expected.is_sorted_by(|(_, a), (_, b)| a <= b));
+
+        for (name, lookup_state) in &states {
+            let found = lookup_state.nearest_k(Vec3::ZERO, 10, LOOKUP_RADIUS, None);
+            assert_eq!(found, expected, "{name} disagrees with Naive");
+
+            let found = lookup_state.nearest_k(Vec3::ZERO, 10, LOOKUP_RADIUS, Some(closest));
+            assert_eq!(
+                found[..9],
+                expected[1..],
+                "{name} did not exclude the entity"
+            );
+
+            let found = lookup_state.nearest_k(Vec3::ZERO, 1_000, LOOKUP_RADIUS, None);
+            assert_eq!(
+                found.len(),
+                39,
+                "{name} returned entities outside max_distance"
+            );
+
+            for max_distance in [-LOOKUP_RADIUS, f32::NAN] {
+                let found = lookup_state.nearest_k(Vec3::ZERO, 10, max_distance, None);
+                assert!(
+                    found.is_empty(),
+                    "{name} ignored a max_distance of {max_distance}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_along_ray() {
+        let states = prepared_lookup_states(100_000);
+        let direction = Dir3::new(Vec3::new(1.0, 0.5, -0.25)).unwrap();
+
+        for (max_t, expected_len) in [(5.0, 11), (f32::INFINITY, 24)] {
+            let (_, naive) = &states[0];
+            let expected = naive.entities_along_ray(Vec3::ZERO, direction, max_t, 0.25);
+
+            assert_eq!(expected.len(), expected_len);
+            assert!(expected.is_sorted_by(|(_, a), (_, b)| a <= b));
+
+            for (name, lookup_state) in &states {
+                let found = lookup_state.entities_along_ray(Vec3::ZERO, direction, max_t, 0.25);
+                assert_eq!(found, expected, "{name} disagrees with Naive");
+            }
+        }
+
+        for (name, lookup_state) in &states {
+            for (max_t, thickness) in [
+                (-1.0, 0.25),
+                (f32::NAN, 0.25),
+                (5.0, -0.25),
+                (5.0, f32::NAN),
+            ] {
+                let found =
+                    lookup_state.entities_along_ray(Vec3::ZERO, direction, max_t, thickness);
+                assert!(
+                    found.is_empty(),
+                    "{name} ignored a max_t of {max_t} or thickness of {thickness}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_in_frustum() {
+        let clip_from_view = Mat4::perspective_infinite_reverse_rh(1.0, 16.0 / 9.0, 0.1);
+        let world_from_view = Transform::from_xyz(-WORLD_SIZE, 1.0, 2.0)
+            .looking_at(Vec3::new(0.0, 0.0, 3.0), Vec3::Y)
+            .to_matrix();
+        let frustum = Frustum::from_clip_from_world_custom_far(
+            &(clip_from_view * world_from_view.inverse()),
+            &world_from_view.w_axis.truncate(),
+            &world_from_view.z_axis.truncate(),
+            WORLD_SIZE,
+        );
+
+        let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+            lookup_state.entities_in_frustum(&frustum)
+        });
+        assert_eq!(found, 8454);
+    }
+
+    #[test]
+    fn test_all_in_cone() {
+        let direction = Dir3::new(Vec3::new(1.0, -0.5, 0.25)).unwrap();
+
+        for (half_angle, expected_len) in [(0.4, 253), (2.0, 4627)] {
+            let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+                lookup_state.entities_in_cone(Vec3::new(-2.0, 1.0, 0.0), direction, half_angle, 5.0)
+            });
+            assert_eq!(found, expected_len);
+        }
+    }
+
+    #[test]
+    fn test_all_in_capsule() {
+        let a = Vec3::new(-3.0, 1.0, 2.0);
+        let b = Vec3::new(4.0, -2.0, 0.5);
+
+        let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+            lookup_state.entities_in_capsule(a, b, 0.5)
+        });
+        assert_eq!(found, 107);
+
+        // a degenerate capsule is a sphere
+        let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+            lookup_state.entities_in_capsule(Vec3::ZERO, Vec3::ZERO, LOOKUP_RADIUS)
+        });
+        assert_eq!(found, 39);
+
+        for radius in [-3.0, f32::NAN] {
+            let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+                lookup_state.entities_in_capsule(a, b, radius)
+            });
+            assert_eq!(found, 0, "a radius of {radius} matched entities");
+        }
+    }
+
+    #[test]
+    fn test_all_in_obb() {
+        let half_extents = Vec3::new(4.0, 1.0, 0.5);
+        let rotation = Quat::from_euler(EulerRot::YXZ, 0.7, -0.3, 1.2);
+
+        let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+            lookup_state.entities_in_obb(Vec3::new(1.0, 2.0, -1.0), half_extents, rotation)
+        });
+        assert_eq!(found, 187);
+
+        // without rotation, an oriented box is just an axis-aligned one
+        let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+            lookup_state.entities_in_obb(Vec3::ZERO, Vec3::new(2.0, 1.0, 3.0), Quat::IDENTITY)
+        });
+        assert_eq!(found, 583);
+    }
+
+    #[test]
+    fn test_all_in_cylinder() {
+        let center = Vec3::new(1.0, 5.0, -2.0);
+
+        let found = assert_all_algorithms_agree(10_000, |lookup_state| {
+            lookup_state.entities_in_cylinder(center, Dir3::Y, LOOKUP_RADIUS, None)
+        });
+        assert_eq!(found, 88);
+
+        let found = assert_all_algorithms_agree(10_000, |lookup_state| {
+            lookup_state.entities_in_cylinder(center, Dir3::Y, LOOKUP_RADIUS, Some((-1.0, 4.0)))
+        });
+        assert_eq!(found, 17);
+
+        let axis = Dir3::new(Vec3::new(1.0, 1.0, 0.0)).unwrap();
+        let found = assert_all_algorithms_agree(10_000, |lookup_state| {
+            lookup_state.entities_in_cylinder(center, axis, LOOKUP_RADIUS, Some((-2.0, 2.0)))
+        });
+        assert_eq!(found, 17);
+
+        for radius in [-LOOKUP_RADIUS, f32::NAN] {
+            let found = assert_all_algorithms_agree(10_000, |lookup_state| {
+                lookup_state.entities_in_cylinder(center, Dir3::Y, radius, None)
+            });
+            assert_eq!(found, 0, "a radius of {radius} matched entities");
+        }
+    }
+
+    #[test]
+    fn test_all_in_shell() {
+        let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+            lookup_state.entities_in_shell(Vec3::new(0.0, -1.0, 2.0), 2.0, 2.5)
+        });
+        assert_eq!(found, 437);
+
+        // without an inner radius, a shell is just a sphere
+        let found = assert_all_algorithms_agree(100_000, |lookup_state| {
+            lookup_state.entities_in_shell(Vec3::ZERO, 0.0, LOOKUP_RADIUS)
+        });
+        assert_eq!(found, 39);
+    }
+
+    #[test]
+    fn test_all_in_radius_with_distance() {
+        for (name, lookup_state) in prepared_lookup_states(100_000) {
+            let mut expected = lookup_state.entities_in_radius(Vec3::ONE, LOOKUP_RADIUS);
+            let mut found = lookup_state.entities_in_radius_with_distance(Vec3::ONE, LOOKUP_RADIUS);
+
+            for (entity, position, distance_squared) in &found {
+                let (_, indexed_position) = lookup_state.entities[entity.index_u32() as usize];
+                assert_eq!(
+                    *position, indexed_position,
+                    "{name} returned a wrong position"
+                );
+                assert_eq!(*distance_squared, position.distance_squared(Vec3::ONE));
+            }
+
+            expected.sort();
+            found.sort_by_key(|(entity, _, _)| *entity);
+            let found: Vec<Entity> = found.into_iter().map(|(entity, _, _)| entity).collect();
+            assert_eq!(
+                found, expected,
+                "{name} returned different entities than in_radius"
+            );
+        }
+    }
+
+    #[test]
+    fn test_all_visit_in_radius() {
+        for (name, lookup_state) in prepared_lookup_states(100_000) {
+            let mut expected = lookup_state.entities_in_radius(Vec3::ONE, LOOKUP_RADIUS);
+            let mut found = Vec::new();
+            lookup_state.visit_in_radius(Vec3::ONE, LOOKUP_RADIUS, &mut |entity, position| {
+                let (_, indexed_position) = lookup_state.entities[entity.index_u32() as usize];
+                assert_eq!(
+                    position, indexed_position,
+                    "{name} visited a wrong position"
+                );
+                found.push(entity);
+            });
+
+            expected.sort();
+            found.sort();
+            assert_eq!(
+                found, expected,
+                "{name} visited different entities than in_radius"
+            );
+        }
+    }
+
+    #[test]
+    fn test_all_count_in_radius() {
+        for radius in [LOOKUP_RADIUS, 4.0, 40.0] {
+            let count = assert_all_algorithms_agree(100_000, |lookup| {
+                let found = lookup.entities_in_radius(Vec3::ONE, radius);
+                assert_eq!(lookup.count_in_radius(Vec3::ONE, radius), found.len());
+                found
+            });
+
+            for (name, lookup_state) in prepared_lookup_states(100_000) {
+                let estimate = lookup_state.estimate_in_radius(Vec3::ONE, radius) as f32;
+                let error = (estimate - count as f32).abs() / count as f32;
+                assert!(
+                    error < 0.25,
+                    "{name} estimated {estimate} entities, found {count}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_batch_in_radius() {
+        ComputeTaskPool::get_or_init(TaskPool::default);
+
+        let queries: Vec<(Vec3, f32)> = (0..64)
+            .map(|i| {
+                (
+                    Vec3::new(i as f32 * 0.3 - 9.0, 1.0, -2.0),
+                    0.5 + (i % 4) as f32,
+                )
+            })
+            .collect();
+
+        for (name, lookup_state) in prepared_lookup_states(10_000) {
+            let results = lookup_state.batch_in_radius(&queries);
+            assert_eq!(results.len(), queries.len());
+
+            for (&(sample_point, radius), found) in queries.iter().zip(results.iter()) {
+                let mut found = found.to_vec();
+                let mut expected = lookup_state.entities_in_radius(sample_point, radius);
+                found.sort();
+                expected.sort();
+                assert_eq!(found, expected, "{name} batch disagrees with in_radius");
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_pairs_within() {
+        for distance in [0.0, 1.0, 5.0] {
+            let mut expected = None;
+
+            for (name, lookup_state) in prepared_lookup_states(2_000) {
+                let mut found = Vec::new();
+                lookup_state.visit_pairs_within(distance, &mut |a, b| {
+                    assert_ne!(a, b, "{name} paired an entity with itself");
+                    found.push((a.min(b), a.max(b)));
+                });
+                found.sort();
+
+                let len = found.len();
+                found.dedup();
+                assert_eq!(found.len(), len, "{name} returned duplicate pairs");
+
+                let expected = expected.get_or_insert_with(|| {
+                    let entities = &lookup_state.entities;
+                    let mut expected = Vec::new();
+                    for (i, &(a, pa)) in entities.iter().enumerate() {
+                        for &(b, pb) in &entities[i + 1..] {
+                            if pa.distance(pb) <= distance {
+                                expected.push((a.min(b), a.max(b)));
+                            }
+                        }
+                    }
+                    expected.sort();
+                    expected
+                });
+                assert_eq!(&found, expected, "{name} disagrees with brute force");
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_pairs_between() {
+        // the two sets share 500 entities
+        let entities = world_with_n_entities(2_000);
+        let (left, right) = (&entities[..1_000], &entities[500..]);
+
+        for distance in [0.0, 1.0] {
+            let mut expected = Vec::new();
+            for &(a, pa) in left {
+                for &(b, pb) in right {
+                    if pa.distance(pb) <= distance {
+                        expected.push((a, b));
+                    }
+                }
+            }
+            expected.sort();
+            assert!(expected.iter().any(|(a, b)| a == b));
+
+            // every algorithm against every other, to cover walking two trees of the same type
+            // as well as the fallback for mixed types
+            for (name, lookup_state) in lookup_states_with(left.to_vec()) {
+                for (other_name, other) in lookup_states_with(right.to_vec()) {
+                    let mut found = Vec::new();
+                    lookup_state.visit_pairs_between(&other, distance, &mut |a, b| {
+                        found.push((a, b));
+                    });
+                    found.sort();
+                    assert_eq!(found, expected, "{name} against {other_name} disagrees");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_overlapping() {
+        fn brute_force(
+            lookup_state: &SpatialLookupState,
+            overlaps: impl Fn(Vec3, f32) -> bool,
+        ) -> Vec<Entity> {
+            let mut expected: Vec<Entity> = lookup_state
+                .entities
+                .iter()
+                .zip(&lookup_state.extents)
+                .filter(|((_, position), extent)| overlaps(*position, **extent))
+                .map(|((entity, _), _)| *entity)
+                .collect();
+            expected.sort();
+            expected
+        }
+
+        let center = Vec3::new(1.0, -2.0, 0.5);
+        let (min, max) = (Vec3::new(-3.0, 0.0, -1.0), Vec3::new(-1.0, 4.0, 2.0));
+
+        for (name, mut lookup_state) in prepared_lookup_states(10_000) {
+            // the default overlap lookups treat every entity as a point
+            if name == "Default methods" {
+                continue;
+            }
+
+            lookup_state.extents = (0..10_000).map(|i| (i % 5) as f32 * 0.4).collect();
+            lookup_state.request_full_rebuild();
+            lookup_state.prepare_algorithm();
+
+            for step in 0..2 {
+                let mut found = lookup_state.entities_overlapping_sphere(center, 1.5);
+                found.sort();
+                let expected = brute_force(&lookup_state, |p, extent| {
+                    p.distance(center) <= 1.5 + extent
+                });
+                assert!(expected.len() > lookup_state.entities_in_radius(center, 1.5).len());
+                assert_eq!(
+                    found, expected,
+                    "{name} sphere overlap disagrees in step {step}"
+                );
+
+                let mut found = lookup_state.entities_overlapping_aabb(min, max);
+                found.sort();
+                let expected = brute_force(&lookup_state, |p, extent| {
+                    p.clamp(min, max).distance_squared(p) <= extent * extent
+                });
+                assert_eq!(
+                    found, expected,
+                    "{name} aabb overlap disagrees in step {step}"
+                );
+
+                // add a far away entity which reaches into both query volumes
+                let entity = Entity::from_raw_u32(10_000).unwrap();
+                lookup_state.upsert_entity_with_extent(entity, Vec3::new(-8.0, -8.0, -8.0), 14.0);
+                lookup_state.prepare_algorithm();
+            }
+
+            // shrinking or removing the large entity must not leave nodes padded too little for
+            // the entities still in them
+            let entity = Entity::from_raw_u32(10_000).unwrap();
+            lookup_state.upsert_entity_with_extent(entity, Vec3::new(-8.0, -8.0, -8.0), 1.0);
+            let grown = Entity::from_raw_u32(10_001).unwrap();
+            let position = center + Vec3::new(0.0, 0.0, 2.7);
+            lookup_state.upsert_entity_with_extent(grown, position, 0.5);
+            lookup_state.upsert_entity_with_extent(grown, position, 3.0);
+            lookup_state.remove_entity(entity);
+            lookup_state.prepare_algorithm();
+
+            let mut found = lookup_state.entities_overlapping_sphere(center, 1.5);
+            found.sort();
+            let expected = brute_force(&lookup_state, |p, extent| {
+                p.distance(center) <= 1.5 + extent
+            });
+            assert_eq!(
+                found, expected,
+                "{name} sphere overlap disagrees after removal"
+            );
+        }
+    }
+
+    #[test]
+    fn test_all_in_radius_sorted() {
+        for (name, lookup_state) in prepared_lookup_states(100_000) {
+            let found = lookup_state.entities_in_radius_sorted(Vec3::ONE, LOOKUP_RADIUS);
+            let distances: Vec<f32> = found
+                .iter()
+                .map(|entity| {
+                    let (_, position) = lookup_state.entities[entity.index_u32() as usize];
+                    position.distance_squared(Vec3::ONE)
+                })
+                .collect();
+
+            assert_eq!(
+                found.len(),
+                lookup_state
+                    .entities_in_radius(Vec3::ONE, LOOKUP_RADIUS)
+                    .len()
+            );
+            assert!(distances.is_sorted(), "{name} did not sort nearest first");
+        }
+    }
+
+    #[test]
+    fn test_all_nearest_iter() {
+        for (name, lookup_state) in prepared_lookup_states(10_000) {
+            let expected = lookup_state.nearest_k(Vec3::ONE, 20, f32::INFINITY, None);
+            let found: Vec<(Entity, f32)> = lookup_state.nearest_iter(Vec3::ONE).take(20).collect();
+            let distances = |results: &[(Entity, f32)]| -> Vec<f32> {
+                results.iter().map(|(_, distance)| *distance).collect()
+            };
+            assert_eq!(
+                distances(&found),
+                distances(&expected),
+                "{name} disagrees with nearest_k"
+            );
+
+            let mut all: Vec<(Entity, f32)> = lookup_state.nearest_iter(Vec3::ONE).collect();
+            assert!(
+                all.is_sorted_by(|(_, a), (_, b)| a <= b),
+                "{name} did not sort nearest first"
+            );
+            all.sort_by_key(|(entity, _)| *entity);
+            all.dedup_by_key(|(entity, _)| *entity);
+            assert_eq!(
+                all.len(),
+                10_000,
+                "{name} did not yield every entity exactly once"
+            );
+        }
+    }
+
+    #[test]
+    fn test_all_on_layers() {
+        /// The tracked entities whose layers match `mask` and whose position is `inside`.
+        fn brute_force(
+            lookup_state: &SpatialLookupState,
+            mask: SpatialLayers,
+            inside: impl Fn(Vec3) -> bool,
+        ) -> Vec<Entity> {
+            let mut expected: Vec<Entity> = lookup_state
+                .entities
+                .iter()
+                .zip(&lookup_state.layers)
+                .filter(|((_, position), layers)| layers.matches(mask) && inside(*position))
+                .map(|((entity, _), _)| *entity)
+                .collect();
+            expected.sort();
+            expected
+        }
+
+        fn sorted(mut found: Vec<Entity>) -> Vec<Entity> {
+            found.sort();
+            found
+        }
+
+        fn assert_agrees(name: &str, lookup_state: &SpatialLookupState) {
+            let direction = Dir3::new(Vec3::new(1.0, 0.5, -0.25)).unwrap();
+            let half = Vec3::new(2.0, 1.0, 3.0);
+
+            for mask in [
+                SpatialLayers(0b10),
+                SpatialLayers::DEFAULT,
+                SpatialLayers::ALL,
+                SpatialLayers::NONE,
+            ] {
+                let mut found = Vec::new();
+                lookup_state
+                    .visit_in_radius_on_layers(Vec3::ZERO, 2.0, mask, &mut |e, _| found.push(e));
+                let expected = brute_force(lookup_state, mask, |p| p.length() <= 2.0);
+                assert_eq!(sorted(found), expected, "{name} in_radius on {mask:?}");
+
+                let found = lookup_state.entities_in_aabb_on_layers(-half, half, mask);
+                let expected = brute_force(lookup_state, mask, |p| p.abs().cmple(half).all());
+                assert_eq!(sorted(found), expected, "{name} in_aabb on {mask:?}");
+
+                let found = lookup_state.entities_in_shell_on_layers(Vec3::ONE, 1.0, 2.0, mask);
+                let expected = brute_force(lookup_state, mask, |p| {
+                    (1.0..=2.0).contains(&p.distance(Vec3::ONE))
+                });
+                assert_eq!(sorted(found), expected, "{name} in_shell on {mask:?}");
+
+                let found =
+                    lookup_state.entities_in_cone_on_layers(Vec3::ZERO, direction, 0.3, 5.0, mask);
+                let unmasked = lookup_state.entities_in_cone(Vec3::ZERO, direction, 0.3, 5.0);
+                let expected: Vec<Entity> = brute_force(lookup_state, mask, |_| true)
+                    .into_iter()
+                    .filter(|entity| unmasked.contains(entity))
+                    .collect();
+                assert_eq!(sorted(found), expected, "{name} in_cone on {mask:?}");
+
+                let found: Vec<Entity> = lookup_state
+                    .entities_along_ray_on_layers(Vec3::ZERO, direction, 5.0, 0.5, mask)
+                    .into_iter()
+                    .map(|(entity, _)| entity)
+                    .collect();
+                let unmasked: Vec<Entity> = lookup_state
+                    .entities_along_ray(Vec3::ZERO, direction, 5.0, 0.5)
+                    .into_iter()
+                    .map(|(entity, _)| entity)
+                    .filter(|&entity| lookup_state.is_on_layers(entity, mask))
+                    .collect();
+                assert_eq!(found, unmasked, "{name} along_ray on {mask:?}");
+
+                // the k nearest entities on the mask, not the k nearest entities filtered by it
+                let mut expected: Vec<(Entity, f32)> = lookup_state
+                    .entities
+                    .iter()
+                    .zip(&lookup_state.layers)
+                    .filter(|(_, layers)| layers.matches(mask))
+                    .map(|(&(entity, position), _)| (entity, position.length()))
+                    .filter(|&(_, distance)| distance <= LOOKUP_RADIUS * 4.0)
+                    .collect();
+                expected.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+                expected.truncate(10);
+                if mask != SpatialLayers::NONE {
+                    assert_eq!(expected.len(), 10);
+                }
+
+                let found = lookup_state.nearest_k_on_layers(
+                    Vec3::ZERO,
+                    10,
+                    LOOKUP_RADIUS * 4.0,
+                    None,
+                    mask,
+                );
+                assert_eq!(found, expected, "{name} nearest_k on {mask:?}");
+
+                let found: Vec<(Entity, f32)> = lookup_state
+                    .nearest_iter_on_layers(Vec3::ZERO, mask)
+                    .take(10)
+                    .collect();
+                assert_eq!(found, expected, "{name} nearest_iter on {mask:?}");
+
+                let mut found = Vec::new();
+                lookup_state.visit_pairs_within_on_layers(0.5, mask, &mut |a, b| {
+                    found.push((a.min(b), a.max(b)));
+                });
+                found.sort();
+                let mut expected = Vec::new();
+                lookup_state.visit_pairs_within(0.5, &mut |a, b| {
+                    if lookup_state.is_on_layers(a, mask) && lookup_state.is_on_layers(b, mask) {
+                        expected.push((a.min(b), a.max(b)));
+                    }
+                });
+                expected.sort();
+                assert_eq!(found, expected, "{name} pairs_within on {mask:?}");
+            }
+        }
+
+        let layers_of = |i: usize| match i % 4 {
+            0 => SpatialLayers::DEFAULT,
+            1 => SpatialLayers(0b10),
+            2 => SpatialLayers(0b100),
+            _ => SpatialLayers(0b110),
+        };
+
+        for (name, mut lookup_state) in lookup_states_with(Vec::new()) {
+            // tracked one by one, so the layers are first set on the prepared algorithm
+            for (i, (entity, position)) in world_with_n_entities(5_000).into_iter().enumerate() {
+                lookup_state.upsert_entity(entity, position);
+                lookup_state.set_layers(entity, layers_of(i));
+            }
+            lookup_state.prepare_algorithm();
+            assert_agrees(name, &lookup_state);
+
+            lookup_state.request_full_rebuild();
+            lookup_state.prepare_algorithm();
+            assert_agrees(name, &lookup_state);
+
+            // changed layers must be picked up by the nodes above the entities too
+            for i in (0..5_000).step_by(7) {
+                let entity = Entity::from_raw_u32(i).unwrap();
+                lookup_state.set_layers(entity, layers_of(i as usize + 1));
+            }
+            lookup_state.prepare_algorithm();
+            assert_agrees(name, &lookup_state);
+        }
+    }
 }
@@ -1,5 +1,13 @@
 //! Naive Spatial Lookup: Just iterate all entities every time!
+use super::geometry::{
+    AabbQuery, ConeQuery, CylinderQuery, FrustumQuery, ObbQuery, OverlapShape, QueryShape,
+    SegmentQuery, ShellQuery, SphereQuery,
+};
+use super::nearest::{Candidate, Closest, KNearest, NearestIter};
 use crate::prelude::*;
+use bevy::camera::primitives::Frustum;
+use bevy::math::FloatOrd;
+use bevy::platform::collections::HashMap;
 use bevy::prelude::*;
 
 /// Naive spatial lookup: just iterate all entities every time.
@@ -10,11 +18,80 @@ use bevy::prelude::*;
 #[derive(Debug, Default)]
 pub struct Naive {
     entities: Vec<(Entity, Vec3)>,
+    /// Bounding sphere radius of each entity in `entities`.
+    extents: Vec<f32>,
+    /// Layers of each entity in `entities`.
+    layers: Vec<SpatialLayers>,
+}
+
+impl Naive {
+    /// Iterates over the entities whose layers match `mask`.
+    fn entities_on_layers(
+        &self,
+        mask: SpatialLayers,
+    ) -> impl Iterator<Item = (usize, Entity, Vec3)> {
+        self.entities
+            .iter()
+            .zip(&self.layers)
+            .enumerate()
+            .filter(move |(_, (_, layers))| layers.matches(mask))
+            .map(|(i, ((entity, position), _))| (i, *entity, *position))
+    }
+
+    /// Returns a list of all entities inside `shape` whose layers match `mask`.
+    fn entities_in_shape(&self, shape: &impl QueryShape, mask: SpatialLayers) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        for (_, entity, position) in self.entities_on_layers(mask) {
+            if shape.contains_point(position) {
+                found_entities.push(entity);
+            }
+        }
+
+        found_entities
+    }
+
+    /// Returns a list of all entities whose bounding sphere intersects `shape` and whose layers
+    /// match `mask`.
+    fn entities_overlapping_shape(
+        &self,
+        shape: &impl OverlapShape,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        for (i, entity, position) in self.entities_on_layers(mask) {
+            if shape.intersects_sphere(position, self.extents[i]) {
+                found_entities.push(entity);
+            }
+        }
+
+        found_entities
+    }
 }
 
 impl SpatialLookupAlgorithm for Naive {
     fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
         self.entities = entities.to_owned();
+        self.extents = vec![0.0; entities.len()];
+        self.layers = vec![SpatialLayers::DEFAULT; entities.len()];
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[f32]) {
+        self.entities = entities.to_owned();
+        self.extents = extents.to_owned();
+        self.layers = vec![SpatialLayers::DEFAULT; entities.len()];
+    }
+
+    fn prepare_with_layers(
+        &mut self,
+        entities: &[(Entity, Vec3)],
+        extents: &[f32],
+        layers: &[SpatialLayers],
+    ) {
+        self.entities = entities.to_owned();
+        self.extents = extents.to_owned();
+        self.layers = layers.to_owned();
     }
 
     fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
@@ -28,4 +105,279 @@ impl SpatialLookupAlgorithm for Naive {
 
         found_entities
     }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        for (entity, position) in &self.entities {
+            if position.distance(sample_point) <= radius {
+                visit(*entity, *position);
+            }
+        }
+    }
+
+    fn visit_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        for (_, entity, position) in self.entities_on_layers(mask) {
+            if position.distance(sample_point) <= radius {
+                visit(entity, position);
+            }
+        }
+    }
+
+    fn entities_in_radius_with_distance(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> Vec<(Entity, Vec3, f32)> {
+        let mut found_entities = Vec::new();
+
+        for (entity, position) in &self.entities {
+            let distance_squared = position.distance_squared(sample_point);
+            if distance_squared.sqrt() <= radius {
+                found_entities.push((*entity, *position, distance_squared));
+            }
+        }
+
+        found_entities
+    }
+
+    fn entities_in_aabb_on_layers(&self, min: Vec3, max: Vec3, mask: SpatialLayers) -> Vec<Entity> {
+        self.entities_in_shape(&AabbQuery { min, max }, mask)
+    }
+
+    fn nearest_k_on_layers(
+        &self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Option<Entity>,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        let mut nearest = KNearest::new(sample_point, k, max_distance, exclude);
+
+        for (_, entity, position) in self.entities_on_layers(mask) {
+            nearest.offer(entity, position);
+        }
+
+        nearest.into_sorted_vec()
+    }
+
+    fn entities_along_ray_on_layers(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        let segment = SegmentQuery {
+            origin,
+            direction: *direction,
+            max_t,
+            thickness,
+        };
+        if segment.is_empty() {
+            return Vec::new();
+        }
+
+        let mut found_entities: Vec<(Entity, f32)> = self
+            .entities_on_layers(mask)
+            .filter_map(|(_, entity, position)| segment.hit(position).map(|t| (entity, t)))
+            .collect();
+        found_entities.sort_by_key(|(entity, t)| (FloatOrd(*t), *entity));
+
+        found_entities
+    }
+
+    fn entities_in_frustum_on_layers(&self, frustum: &Frustum, mask: SpatialLayers) -> Vec<Entity> {
+        self.entities_in_shape(&FrustumQuery { frustum }, mask)
+    }
+
+    fn entities_in_cone_on_layers(
+        &self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let cone = ConeQuery {
+            apex,
+            direction: *direction,
+            half_angle,
+            range,
+        };
+
+        self.entities_in_shape(&cone, mask)
+    }
+
+    fn entities_in_capsule_on_layers(
+        &self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let capsule = SegmentQuery::between(a, b, radius);
+        if capsule.is_empty() {
+            return Vec::new();
+        }
+
+        self.entities_in_shape(&capsule, mask)
+    }
+
+    fn entities_in_obb_on_layers(
+        &self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let obb = ObbQuery {
+            center,
+            half_extents,
+            rotation,
+        };
+
+        self.entities_in_shape(&obb, mask)
+    }
+
+    fn entities_in_cylinder_on_layers(
+        &self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let cylinder = CylinderQuery {
+            center,
+            axis: *axis,
+            radius,
+            height_range,
+        };
+        if cylinder.is_empty() {
+            return Vec::new();
+        }
+
+        self.entities_in_shape(&cylinder, mask)
+    }
+
+    fn entities_in_shell_on_layers(
+        &self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let shell = ShellQuery {
+            center,
+            min_radius,
+            max_radius,
+        };
+
+        self.entities_in_shape(&shell, mask)
+    }
+
+    fn entities_overlapping_sphere_on_layers(
+        &self,
+        center: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        self.entities_overlapping_shape(&SphereQuery { center, radius }, mask)
+    }
+
+    fn entities_overlapping_aabb_on_layers(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        self.entities_overlapping_shape(&AabbQuery { min, max }, mask)
+    }
+
+    fn visit_pairs_within_on_layers(
+        &self,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        if distance.is_nan() || distance < 0.0 {
+            return;
+        }
+
+        // Bucket the entities into cells as large as `distance`, so that each entity only has to be
+        // tested against the entities in its own and the neighbouring cells.
+        let cell_of = |position: Vec3| (position / distance).floor().as_ivec3();
+        let mut cells: HashMap<IVec3, Vec<usize>> = HashMap::default();
+        for (i, _, position) in self.entities_on_layers(mask) {
+            cells.entry(cell_of(position)).or_default().push(i);
+        }
+
+        for (i, entity, position) in self.entities_on_layers(mask) {
+            let cell = cell_of(position);
+
+            // cells saturate at the edges of the i32 range, so neighbours may repeat there
+            let mut neighbours = [IVec3::ZERO; 27];
+            for (n, neighbour) in neighbours.iter_mut().enumerate() {
+                let offset = IVec3::new(n as i32 % 3, n as i32 / 3 % 3, n as i32 / 9) - 1;
+                *neighbour = cell.saturating_add(offset);
+            }
+            neighbours.sort_unstable_by_key(|cell| cell.to_array());
+
+            for (n, neighbour) in neighbours.iter().enumerate() {
+                if n > 0 && neighbours[n - 1] == *neighbour {
+                    continue;
+                }
+
+                for &j in cells.get(neighbour).into_iter().flatten() {
+                    // every pair is found from both sides, only visit it from the first one
+                    let (other, other_position) = self.entities[j];
+                    if i < j && position.distance(other_position) <= distance {
+                        visit(entity, other);
+                    }
+                }
+            }
+        }
+    }
+
+    fn nearest_iter_on_layers(
+        &self,
+        sample_point: Vec3,
+        mask: SpatialLayers,
+    ) -> Box<dyn Iterator<Item = (Entity, f32)> + '_> {
+        // Every entity is queued up front, there are no nodes to expand.
+        let queue = self
+            .entities_on_layers(mask)
+            .map(|(_, entity, position)| Closest {
+                distance_squared: position.distance_squared(sample_point),
+                item: Candidate::<()>::Entity(entity),
+            })
+            .collect();
+
+        Box::new(NearestIter::new(queue, |_, _| {}))
+    }
+
+    fn supports_layers(&self) -> bool {
+        true
+    }
+
+    fn reports_positions(&self) -> bool {
+        true
+    }
+
+    fn returns_unique_entities(&self) -> Option<UniqueEntities> {
+        // SAFETY: every lookup of `Naive` visits each entity at most once.
+        Some(unsafe { UniqueEntities::new() })
+    }
 }
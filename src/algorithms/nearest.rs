@@ -0,0 +1,154 @@
+//! Helpers for best-first nearest neighbour searches.
+
+use bevy::math::FloatOrd;
+use bevy::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Priority queue entry ordered so that `BinaryHeap` pops the *smallest* distance first.
+///
+/// Used by the tree algorithms to visit nodes closest to the sample point first.
+pub(crate) struct Closest<T> {
+    pub distance_squared: f32,
+    pub item: T,
+}
+
+impl<T> PartialEq for Closest<T> {
+    fn eq(&self, other: &Self) -> bool {
+        FloatOrd(self.distance_squared) == FloatOrd(other.distance_squared)
+    }
+}
+
+impl<T> Eq for Closest<T> {}
+
+impl<T> PartialOrd for Closest<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Closest<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        FloatOrd(other.distance_squared).cmp(&FloatOrd(self.distance_squared))
+    }
+}
+
+/// Keeps track of the `k` nearest entities offered so far.
+pub(crate) struct KNearest {
+    k: usize,
+    sample_point: Vec3,
+    max_distance_squared: f32,
+    exclude: Option<Entity>,
+    /// Max-heap, so the current worst candidate can be replaced cheaply.
+    found: BinaryHeap<(FloatOrd, Entity)>,
+}
+
+impl KNearest {
+    /// A negative or NaN `max_distance` matches nothing.
+    pub fn new(sample_point: Vec3, k: usize, max_distance: f32, exclude: Option<Entity>) -> Self {
+        // squaring would turn a negative limit into a positive one
+        let max_distance_squared = if max_distance >= 0.0 {
+            max_distance * max_distance
+        } else {
+            f32::NEG_INFINITY
+        };
+
+        Self {
+            k,
+            sample_point,
+            max_distance_squared,
+            exclude,
+            found: BinaryHeap::with_capacity(k),
+        }
+    }
+
+    /// Squared distance beyond which nothing can make it into the result anymore.
+    ///
+    /// Nodes further away than this can be skipped.
+    pub fn bound_squared(&self) -> f32 {
+        if self.found.len() < self.k {
+            self.max_distance_squared
+        } else {
+            self.found
+                .peek()
+                .map_or(f32::NEG_INFINITY, |(distance, _)| distance.0)
+        }
+    }
+
+    /// Offers an entity as a candidate, keeping it if it is among the `k` nearest so far.
+    pub fn offer(&mut self, entity: Entity, position: Vec3) {
+        if self.k == 0 || self.exclude == Some(entity) {
+            return;
+        }
+
+        let distance_squared = position.distance_squared(self.sample_point);
+        if distance_squared > self.max_distance_squared {
+            return;
+        }
+
+        if self.found.len() < self.k {
+            self.found.push((FloatOrd(distance_squared), entity));
+        } else if self
+            .found
+            .peek()
+            .is_some_and(|(worst, _)| distance_squared < worst.0)
+        {
+            self.found.pop();
+            self.found.push((FloatOrd(distance_squared), entity));
+        }
+    }
+
+    /// Returns the found entities and their distances, nearest first.
+    pub fn into_sorted_vec(self) -> Vec<(Entity, f32)> {
+        self.found
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(distance_squared, entity)| (entity, distance_squared.0.sqrt()))
+            .collect()
+    }
+}
+
+/// Either a tree node still to be expanded, or an entity ready to be yielded.
+pub(crate) enum Candidate<N> {
+    Node(N),
+    Entity(Entity),
+}
+
+/// Priority queue of candidates, closest first.
+pub(crate) type CandidateQueue<N> = BinaryHeap<Closest<Candidate<N>>>;
+
+/// Lazily yields entities and their distances, nearest first.
+///
+/// Nodes are queued by the distance to their bounds and entities by their exact distance. Because
+/// a node is never further away than anything inside it, an entity popped from the queue is
+/// always closer than everything not yet yielded. `expand` pushes the children or entities of a
+/// node into the queue.
+pub(crate) struct NearestIter<N, X> {
+    queue: CandidateQueue<N>,
+    expand: X,
+}
+
+impl<N, X: FnMut(N, &mut CandidateQueue<N>)> NearestIter<N, X> {
+    pub fn new(queue: CandidateQueue<N>, expand: X) -> Self {
+        Self { queue, expand }
+    }
+}
+
+impl<N, X: FnMut(N, &mut CandidateQueue<N>)> Iterator for NearestIter<N, X> {
+    type Item = (Entity, f32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Closest {
+            distance_squared,
+            item,
+        }) = self.queue.pop()
+        {
+            match item {
+                Candidate::Entity(entity) => return Some((entity, distance_squared.sqrt())),
+                Candidate::Node(node) => (self.expand)(node, &mut self.queue),
+            }
+        }
+
+        None
+    }
+}
@@ -1,9 +1,17 @@
 //! Incrementally-updated Octree spatial lookup.
 
+use bevy::math::FloatOrd;
 use bevy::prelude::*;
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{BinaryHeap, HashMap};
 
-use crate::SpatialLookupAlgorithm;
+use super::geometry::{
+    self, AabbQuery, ConeQuery, CylinderQuery, FrustumQuery, ObbQuery, OverlapShape, QueryShape,
+    SegmentQuery, ShellQuery, SphereQuery,
+};
+use super::nearest::{Candidate, CandidateQueue, Closest, KNearest, NearestIter};
+use crate::{SpatialLayers, SpatialLookupAlgorithm, UniqueEntities};
+use bevy::camera::primitives::Frustum;
 
 /// Configuration parameters for the Octree.
 ///
@@ -54,13 +62,22 @@ impl AabbCube {
         d.x.abs() <= h && d.y.abs() <= h && d.z.abs() <= h
     }
 
-    fn intersects_sphere(&self, c: Vec3, r: f32) -> bool {
+    /// Returns the `(min, max)` corners of the cube, grown by `padding` on every side.
+    fn min_max(&self, padding: f32) -> (Vec3, Vec3) {
+        let h = Vec3::splat(self.half + padding);
+        (self.center - h, self.center + h)
+    }
+
+    fn intersects_sphere(&self, c: Vec3, r: f32, padding: f32) -> bool {
         // Compute squared distance from sphere center to AABB
-        let min = self.center - Vec3::splat(self.half);
-        let max = self.center + Vec3::splat(self.half);
+        let (min, max) = self.min_max(padding);
 
         let mut d2 = 0.0;
-        for (ci, mi, ma) in [(c.x, min.x, max.x), (c.y, min.y, max.y), (c.z, min.z, max.z)] {
+        for (ci, mi, ma) in [
+            (c.x, min.x, max.x),
+            (c.y, min.y, max.y),
+            (c.z, min.z, max.z),
+        ] {
             let v = if ci < mi {
                 mi - ci
             } else if ci > ma {
@@ -79,21 +96,36 @@ struct Node {
     bounds: AabbCube,
     depth: u8,
     children: Option<[usize; 8]>,
-    bucket: Vec<(Entity, Vec3)>, // only used when leaf
+    bucket: Vec<(Entity, Vec3, SpatialLayers)>, // only used when leaf
+    /// Largest extent of the entities under this node. May be larger than needed until the
+    /// entities it was raised for are gone, see `refresh_path`.
+    max_extent: f32,
+    /// Union of the layers of the entities under this node. Like `max_extent`, it may contain
+    /// more layers than needed until the entities they were added for are gone.
+    layers: SpatialLayers,
 }
 
 impl Node {
     fn is_leaf(&self) -> bool {
         self.children.is_none()
     }
+
+    /// Iterates over the entities in the bucket whose layers match `mask`.
+    fn bucket_on_layers(&self, mask: SpatialLayers) -> impl Iterator<Item = (Entity, Vec3)> + '_ {
+        self.bucket
+            .iter()
+            .filter(move |(_, _, layers)| layers.matches(mask))
+            .map(|&(e, p, _)| (e, p))
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Octree {
     cfg: OctreeConfig,
     built: bool,
-    nodes: Vec<Node>,                 // arena
+    nodes: Vec<Node>,                    // arena
     entity_leaf: HashMap<Entity, usize>, // entity -> leaf node index
+    entity_extent: HashMap<Entity, f32>, // entity -> bounding sphere radius, if not a point
 }
 
 impl Octree {
@@ -103,6 +135,7 @@ impl Octree {
             built: false,
             nodes: Vec::new(),
             entity_leaf: HashMap::default(),
+            entity_extent: HashMap::default(),
         }
     }
 
@@ -113,10 +146,15 @@ impl Octree {
         if entities.is_empty() {
             // Create a tiny root so inserts can still work later.
             self.nodes.push(Node {
-                bounds: AabbCube { center: Vec3::ZERO, half: 1.0 },
+                bounds: AabbCube {
+                    center: Vec3::ZERO,
+                    half: 1.0,
+                },
                 depth: 0,
                 children: None,
                 bucket: Vec::new(),
+                max_extent: 0.0,
+                layers: SpatialLayers::NONE,
             });
             self.built = true;
             return;
@@ -142,10 +180,12 @@ impl Octree {
             depth: 0,
             children: None,
             bucket: Vec::new(),
+            max_extent: 0.0,
+            layers: SpatialLayers::NONE,
         });
 
         for &(e, p) in entities {
-            self.insert_internal(e, p);
+            self.insert_internal(e, p, SpatialLayers::DEFAULT);
         }
 
         self.built = true;
@@ -172,10 +212,15 @@ impl Octree {
             // Create new root node at end, then move it to index 0 by swapping.
             let new_root_index = self.nodes.len();
             self.nodes.push(Node {
-                bounds: AabbCube { center: new_center, half: new_half },
+                bounds: AabbCube {
+                    center: new_center,
+                    half: new_half,
+                },
                 depth: 0,
                 children: None,
                 bucket: Vec::new(),
+                max_extent: 0.0,
+                layers: SpatialLayers::NONE,
             });
 
             // Make new root the actual root by swapping with index 0 (simplest arena trick).
@@ -200,6 +245,438 @@ impl Octree {
 
             // Fix up any entity_leaf mappings that pointed to swapped nodes.
             self.fix_leaf_indices_after_swap(child_node_idx, old_root_new_index);
+
+            // The old root is the only non-empty child.
+            self.nodes[0].max_extent = self.nodes[child_node_idx].max_extent;
+            self.nodes[0].layers = self.nodes[child_node_idx].layers;
+        }
+    }
+
+    /// Squared distance from `p` to the (loose) bounds of node `node_idx`.
+    fn node_distance_squared(&self, node_idx: usize, p: Vec3) -> f32 {
+        let (min, max) = self.nodes[node_idx].bounds.min_max(self.cfg.loose_padding);
+        geometry::aabb_distance_squared(min, max, p)
+    }
+
+    /// Returns a list of all entities inside `shape` whose layers match `mask`.
+    fn entities_in_shape(&self, shape: &impl QueryShape, mask: SpatialLayers) -> Vec<Entity> {
+        let mut out = Vec::new();
+        self.visit_shape(shape, mask, &mut |e, _| out.push(e));
+        out
+    }
+
+    /// Calls `visit` for every entity inside `shape` whose layers match `mask`.
+    ///
+    /// Nodes which lie completely inside the shape are visited without testing each position.
+    fn visit_shape(
+        &self,
+        shape: &impl QueryShape,
+        mask: SpatialLayers,
+        visit: &mut impl FnMut(Entity, Vec3),
+    ) {
+        if !self.built || self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = vec![0usize];
+
+        while let Some(idx) = stack.pop() {
+            let n = &self.nodes[idx];
+            if !n.layers.matches(mask) {
+                continue;
+            }
+
+            // Entities may sit up to `loose_padding` outside their leaf, see `update_internal`.
+            let (min, max) = n.bounds.min_max(self.cfg.loose_padding);
+            if !shape.intersects_aabb(min, max) {
+                continue;
+            }
+
+            if shape.contains_aabb(min, max) {
+                self.visit_all(idx, mask, visit);
+                continue;
+            }
+
+            if let Some(children) = n.children {
+                stack.extend_from_slice(&children);
+            } else {
+                for (e, p) in n.bucket_on_layers(mask) {
+                    if shape.contains_point(p) {
+                        visit(e, p);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the number of entities inside `shape`.
+    ///
+    /// Nodes which lie completely inside the shape are counted without testing each entity.
+    fn count_in_shape(&self, shape: &impl QueryShape) -> usize {
+        if !self.built || self.nodes.is_empty() {
+            return 0;
+        }
+
+        let mut count = 0;
+        let mut stack = vec![0usize];
+
+        while let Some(idx) = stack.pop() {
+            let n = &self.nodes[idx];
+            let (min, max) = n.bounds.min_max(self.cfg.loose_padding);
+            if !shape.intersects_aabb(min, max) {
+                continue;
+            }
+
+            if shape.contains_aabb(min, max) {
+                count += self.node_len(idx);
+                continue;
+            }
+
+            if let Some(children) = n.children {
+                stack.extend_from_slice(&children);
+            } else {
+                count += n
+                    .bucket
+                    .iter()
+                    .filter(|(_, p, _)| shape.contains_point(*p))
+                    .count();
+            }
+        }
+
+        count
+    }
+
+    /// Estimates the number of entities in radius of `sample_point` from bucket sizes.
+    ///
+    /// Leaves which are only partially covered by the sphere contribute their bucket size scaled
+    /// by the estimated share of their (loose) bounds inside the sphere.
+    fn estimate_in_sphere(&self, sample_point: Vec3, radius: f32) -> f32 {
+        if !self.built || self.nodes.is_empty() {
+            return 0.0;
+        }
+
+        let sphere = SphereQuery {
+            center: sample_point,
+            radius,
+        };
+        let mut estimate = 0.0;
+        let mut stack = vec![0usize];
+
+        while let Some(idx) = stack.pop() {
+            let n = &self.nodes[idx];
+            let (min, max) = n.bounds.min_max(self.cfg.loose_padding);
+            if !sphere.intersects_aabb(min, max) {
+                continue;
+            }
+
+            if sphere.contains_aabb(min, max) {
+                estimate += self.node_len(idx) as f32;
+                continue;
+            }
+
+            if let Some(children) = n.children {
+                stack.extend_from_slice(&children);
+            } else {
+                let covered =
+                    geometry::aabb_sphere_overlap_fraction(min, max, sample_point, radius);
+                estimate += n.bucket.len() as f32 * covered;
+            }
+        }
+
+        estimate
+    }
+
+    /// Calls `visit` for every pair of entities under `node_idx` which are within `distance` of
+    /// each other and whose layers match `mask`.
+    fn visit_pairs_in(
+        &self,
+        node_idx: usize,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        let n = &self.nodes[node_idx];
+        if !n.layers.matches(mask) {
+            return;
+        }
+
+        if let Some(children) = n.children {
+            for (i, &a) in children.iter().enumerate() {
+                self.visit_pairs_in(a, distance, mask, visit);
+                for &b in &children[i + 1..] {
+                    self.visit_pairs_between(a, self, b, distance, mask, visit);
+                }
+            }
+        } else {
+            for (i, &(a, pa, layers)) in n.bucket.iter().enumerate() {
+                if !layers.matches(mask) {
+                    continue;
+                }
+
+                for &(b, pb, other_layers) in &n.bucket[i + 1..] {
+                    if other_layers.matches(mask) && pa.distance(pb) <= distance {
+                        visit(a, b);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `visit` for every pair of one entity under node `a` and one under node `b` of `other`
+    /// which are within `distance` of each other and whose layers match `mask`, descending into
+    /// the larger node first.
+    ///
+    /// `other` may be this octree itself.
+    fn visit_pairs_between(
+        &self,
+        a: usize,
+        other: &Octree,
+        b: usize,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        let (na, nb) = (&self.nodes[a], &other.nodes[b]);
+        if !na.layers.matches(mask) || !nb.layers.matches(mask) {
+            return;
+        }
+
+        let (a_min, a_max) = na.bounds.min_max(self.cfg.loose_padding);
+        let (b_min, b_max) = nb.bounds.min_max(other.cfg.loose_padding);
+        if geometry::aabb_aabb_distance_squared(a_min, a_max, b_min, b_max) > distance * distance {
+            return;
+        }
+
+        match (na.children, nb.children) {
+            (None, None) => {
+                for (ea, pa) in na.bucket_on_layers(mask) {
+                    for (eb, pb) in nb.bucket_on_layers(mask) {
+                        if pa.distance(pb) <= distance {
+                            visit(ea, eb);
+                        }
+                    }
+                }
+            }
+            (Some(children), None) => {
+                for c in children {
+                    self.visit_pairs_between(c, other, b, distance, mask, visit);
+                }
+            }
+            (None, Some(children)) => {
+                for c in children {
+                    self.visit_pairs_between(a, other, c, distance, mask, visit);
+                }
+            }
+            (Some(children), Some(other_children)) => {
+                if na.bounds.half >= nb.bounds.half {
+                    for c in children {
+                        self.visit_pairs_between(c, other, b, distance, mask, visit);
+                    }
+                } else {
+                    for c in other_children {
+                        self.visit_pairs_between(a, other, c, distance, mask, visit);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `visit` for every entity whose bounding sphere intersects `shape` and whose layers
+    /// match `mask`.
+    ///
+    /// Node bounds are grown by their `max_extent` on top of the loose padding for pruning.
+    fn visit_overlapping(
+        &self,
+        shape: &impl OverlapShape,
+        mask: SpatialLayers,
+        visit: &mut impl FnMut(Entity),
+    ) {
+        if !self.built || self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = vec![0usize];
+
+        while let Some(idx) = stack.pop() {
+            let n = &self.nodes[idx];
+            if !n.layers.matches(mask) {
+                continue;
+            }
+
+            let (min, max) = n.bounds.min_max(self.cfg.loose_padding + n.max_extent);
+            if !shape.intersects_aabb(min, max) {
+                continue;
+            }
+
+            // every entity position is inside the shape, so every extent overlaps it
+            let (inner_min, inner_max) = n.bounds.min_max(self.cfg.loose_padding);
+            if shape.contains_aabb(inner_min, inner_max) {
+                self.visit_all(idx, mask, &mut |e, _| visit(e));
+                continue;
+            }
+
+            if let Some(children) = n.children {
+                stack.extend_from_slice(&children);
+            } else {
+                for (e, p) in n.bucket_on_layers(mask) {
+                    let extent = self.entity_extent.get(&e).copied().unwrap_or_default();
+                    if shape.intersects_sphere(p, extent) {
+                        visit(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn extent(&self, e: Entity) -> f32 {
+        self.entity_extent.get(&e).copied().unwrap_or_default()
+    }
+
+    /// Replaces the extents of all entities with `extents`, without touching the tree.
+    fn assign_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[f32]) {
+        self.entity_extent.clear();
+        for (&(e, _), &extent) in entities.iter().zip(extents) {
+            if extent > 0.0 {
+                self.entity_extent.insert(e, extent);
+            }
+        }
+    }
+
+    fn set_extent(&mut self, e: Entity, extent: f32) {
+        let old = self.extent(e);
+        if extent > 0.0 {
+            self.entity_extent.insert(e, extent);
+        } else {
+            self.entity_extent.remove(&e);
+        }
+
+        if extent != old
+            && let Some(&leaf) = self.entity_leaf.get(&e)
+        {
+            self.refresh_path(leaf);
+        }
+    }
+
+    /// Sets the layers of `e` in its bucket, and refreshes the union of layers above it.
+    fn set_layers(&mut self, e: Entity, layers: SpatialLayers) {
+        let Some(&leaf) = self.entity_leaf.get(&e) else {
+            return;
+        };
+
+        if let Some(entry) = self.nodes[leaf]
+            .bucket
+            .iter_mut()
+            .find(|(ent, _, _)| *ent == e)
+            && entry.2 != layers
+        {
+            entry.2 = layers;
+            self.refresh_path(leaf);
+        }
+    }
+
+    /// Recomputes `max_extent` and `layers` of the leaf `leaf_idx` and of every node above it.
+    fn refresh_path(&mut self, leaf_idx: usize) {
+        // Nodes split their bounds evenly, so the child containing the center of the leaf is
+        // always the next node on the path to it.
+        let target = self.nodes[leaf_idx].bounds.center;
+        let mut path = vec![0usize];
+        let mut idx = 0usize;
+        while idx != leaf_idx {
+            let Some(children) = self.nodes[idx].children else {
+                return;
+            };
+            idx = children[self.child_index(self.nodes[idx].bounds.center, target)];
+            path.push(idx);
+        }
+
+        self.summarize_leaf(leaf_idx);
+        for &idx in path.iter().rev().skip(1) {
+            self.summarize_branch(idx);
+        }
+    }
+
+    /// Recomputes `max_extent` and `layers` of `node_idx` and every node below it.
+    fn recompute_summaries(&mut self, node_idx: usize) {
+        match self.nodes[node_idx].children {
+            Some(children) => {
+                for c in children {
+                    self.recompute_summaries(c);
+                }
+                self.summarize_branch(node_idx);
+            }
+            None => self.summarize_leaf(node_idx),
+        }
+    }
+
+    /// Sets `max_extent` and `layers` of the leaf `leaf_idx` from the entities in its bucket.
+    fn summarize_leaf(&mut self, leaf_idx: usize) {
+        let bucket = &self.nodes[leaf_idx].bucket;
+        let max_extent = bucket
+            .iter()
+            .map(|&(e, _, _)| self.extent(e))
+            .fold(0.0, f32::max);
+        let layers = bucket
+            .iter()
+            .fold(0, |union, (_, _, layers)| union | layers.0);
+
+        self.nodes[leaf_idx].max_extent = max_extent;
+        self.nodes[leaf_idx].layers = SpatialLayers(layers);
+    }
+
+    /// Sets `max_extent` and `layers` of the branch `node_idx` from its children.
+    fn summarize_branch(&mut self, node_idx: usize) {
+        let children = self.nodes[node_idx].children.unwrap();
+        let max_extent = children
+            .iter()
+            .map(|&c| self.nodes[c].max_extent)
+            .fold(0.0, f32::max);
+        let layers = children
+            .iter()
+            .fold(0, |union, &c| union | self.nodes[c].layers.0);
+
+        self.nodes[node_idx].max_extent = max_extent;
+        self.nodes[node_idx].layers = SpatialLayers(layers);
+    }
+
+    /// Returns the number of entities stored under `node_idx`.
+    fn node_len(&self, node_idx: usize) -> usize {
+        let mut len = 0;
+        let mut stack = vec![node_idx];
+
+        while let Some(idx) = stack.pop() {
+            let n = &self.nodes[idx];
+            if let Some(children) = n.children {
+                stack.extend_from_slice(&children);
+            } else {
+                len += n.bucket.len();
+            }
+        }
+
+        len
+    }
+
+    /// Calls `visit` for every entity stored under `node_idx` whose layers match `mask`.
+    fn visit_all(
+        &self,
+        node_idx: usize,
+        mask: SpatialLayers,
+        visit: &mut impl FnMut(Entity, Vec3),
+    ) {
+        let mut stack = vec![node_idx];
+
+        while let Some(idx) = stack.pop() {
+            let n = &self.nodes[idx];
+            if !n.layers.matches(mask) {
+                continue;
+            }
+
+            if let Some(children) = n.children {
+                stack.extend_from_slice(&children);
+            } else {
+                for (e, p) in n.bucket_on_layers(mask) {
+                    visit(e, p);
+                }
+            }
         }
     }
 
@@ -207,7 +684,7 @@ impl Octree {
         // If either swapped node is a leaf, update entity->leaf mappings for entities in that leaf.
         for &idx in [a, b].iter() {
             if self.nodes[idx].is_leaf() {
-                for &(e, _) in &self.nodes[idx].bucket {
+                for &(e, _, _) in &self.nodes[idx].bucket {
                     self.entity_leaf.insert(e, idx);
                 }
             }
@@ -216,9 +693,15 @@ impl Octree {
 
     fn child_index(&self, center: Vec3, p: Vec3) -> usize {
         let mut idx = 0usize;
-        if p.x >= center.x { idx |= 1; }
-        if p.y >= center.y { idx |= 2; }
-        if p.z >= center.z { idx |= 4; }
+        if p.x >= center.x {
+            idx |= 1;
+        }
+        if p.y >= center.y {
+            idx |= 2;
+        }
+        if p.z >= center.z {
+            idx |= 4;
+        }
         idx
     }
 
@@ -235,20 +718,37 @@ impl Octree {
         let child_half = half * 0.5;
 
         let mut children = [0usize; 8];
-        for i in 0..8 {
-            let ox = if (i & 1) != 0 { child_half } else { -child_half };
-            let oy = if (i & 2) != 0 { child_half } else { -child_half };
-            let oz = if (i & 4) != 0 { child_half } else { -child_half };
+        for (i, child) in children.iter_mut().enumerate() {
+            let ox = if (i & 1) != 0 {
+                child_half
+            } else {
+                -child_half
+            };
+            let oy = if (i & 2) != 0 {
+                child_half
+            } else {
+                -child_half
+            };
+            let oz = if (i & 4) != 0 {
+                child_half
+            } else {
+                -child_half
+            };
             let child_center = center + Vec3::new(ox, oy, oz);
 
             let idx = self.nodes.len();
             self.nodes.push(Node {
-                bounds: AabbCube { center: child_center, half: child_half },
+                bounds: AabbCube {
+                    center: child_center,
+                    half: child_half,
+                },
                 depth: depth + 1,
                 children: None,
                 bucket: Vec::new(),
+                max_extent: 0.0,
+                layers: SpatialLayers::NONE,
             });
-            children[i] = idx;
+            *child = idx;
         }
 
         // Take bucket and redistribute
@@ -257,21 +757,26 @@ impl Octree {
 
         self.nodes[node_idx].children = Some(children);
 
-        for (e, p) in bucket {
-            self.insert_into(node_idx, e, p);
+        for (e, p, layers) in bucket {
+            self.insert_into(node_idx, e, p, layers);
         }
     }
 
-    fn insert_into(&mut self, node_idx: usize, e: Entity, p: Vec3) {
+    fn insert_into(&mut self, node_idx: usize, e: Entity, p: Vec3, layers: SpatialLayers) {
+        let extent = self.extent(e);
+        let n = &mut self.nodes[node_idx];
+        n.max_extent = n.max_extent.max(extent);
+        n.layers = SpatialLayers(n.layers.0 | layers.0);
+
         if let Some(children) = self.nodes[node_idx].children {
             let ci = self.child_index(self.nodes[node_idx].bounds.center, p);
             let child = children[ci];
-            self.insert_into(child, e, p);
+            self.insert_into(child, e, p, layers);
             return;
         }
 
         // leaf
-        self.nodes[node_idx].bucket.push((e, p));
+        self.nodes[node_idx].bucket.push((e, p, layers));
         self.entity_leaf.insert(e, node_idx);
 
         let len = self.nodes[node_idx].bucket.len();
@@ -280,46 +785,61 @@ impl Octree {
         }
     }
 
-    fn insert_internal(&mut self, e: Entity, p: Vec3) {
+    fn insert_internal(&mut self, e: Entity, p: Vec3, layers: SpatialLayers) {
         if self.nodes.is_empty() {
             self.nodes.push(Node {
-                bounds: AabbCube { center: p, half: self.cfg.min_half_size.max(1.0) },
+                bounds: AabbCube {
+                    center: p,
+                    half: self.cfg.min_half_size.max(1.0),
+                },
                 depth: 0,
                 children: None,
                 bucket: Vec::new(),
+                max_extent: 0.0,
+                layers: SpatialLayers::NONE,
             });
         }
         self.ensure_root_contains(p);
-        self.insert_into(0, e, p);
+        self.insert_into(0, e, p, layers);
     }
 
-    fn remove_internal(&mut self, e: Entity) {
-        let Some(leaf) = self.entity_leaf.remove(&e) else { return; };
+    /// Removes `e` from its bucket, returning the layers it was stored with.
+    fn remove_internal(&mut self, e: Entity) -> Option<SpatialLayers> {
+        let leaf = self.entity_leaf.remove(&e)?;
         let bucket = &mut self.nodes[leaf].bucket;
 
-        if let Some(i) = bucket.iter().position(|(ent, _)| *ent == e) {
-            bucket.swap_remove(i);
-        }
+        let i = bucket.iter().position(|(ent, _, _)| *ent == e)?;
+        let (_, _, layers) = bucket.swap_remove(i);
+
+        // shrink the padding and layers of the nodes above, which may have been raised for this
+        // entity
+        self.refresh_path(leaf);
         // NOTE: we intentionally do not merge nodes on removal (cheap + stable).
+
+        Some(layers)
     }
 
     fn update_internal(&mut self, e: Entity, p: Vec3) {
         let Some(&leaf) = self.entity_leaf.get(&e) else {
-            self.insert_internal(e, p);
+            self.insert_internal(e, p, SpatialLayers::DEFAULT);
             return;
         };
 
         // If still fits within the leaf (loose), update in place
         if self.nodes[leaf].bounds.contains(p, self.cfg.loose_padding) {
-            if let Some(i) = self.nodes[leaf].bucket.iter().position(|(ent, _)| *ent == e) {
+            if let Some(i) = self.nodes[leaf]
+                .bucket
+                .iter()
+                .position(|(ent, _, _)| *ent == e)
+            {
                 self.nodes[leaf].bucket[i].1 = p;
             }
             return;
         }
 
         // Otherwise reinsert
-        self.remove_internal(e);
-        self.insert_internal(e, p);
+        let layers = self.remove_internal(e).unwrap_or_default();
+        self.insert_internal(e, p, layers);
     }
 }
 
@@ -332,18 +852,85 @@ impl SpatialLookupAlgorithm for Octree {
         self.build_from_entities(entities);
     }
 
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[f32]) {
+        self.prepare(entities);
+
+        // extents are refreshed even if the tree itself is kept
+        self.assign_extents(entities, extents);
+
+        if !self.nodes.is_empty() {
+            self.recompute_summaries(0);
+        }
+    }
+
+    fn prepare_with_layers(
+        &mut self,
+        entities: &[(Entity, Vec3)],
+        extents: &[f32],
+        layers: &[SpatialLayers],
+    ) {
+        self.prepare(entities);
+
+        // extents and layers are refreshed even if the tree itself is kept
+        self.assign_extents(entities, extents);
+
+        let entity_layers: HashMap<Entity, SpatialLayers> = entities
+            .iter()
+            .zip(layers)
+            .filter(|&(_, &layers)| layers != SpatialLayers::DEFAULT)
+            .map(|(&(e, _), &layers)| (e, layers))
+            .collect();
+        for n in &mut self.nodes {
+            for (e, _, layers) in &mut n.bucket {
+                *layers = entity_layers
+                    .get(e)
+                    .copied()
+                    .unwrap_or(SpatialLayers::DEFAULT);
+            }
+        }
+
+        if !self.nodes.is_empty() {
+            self.recompute_summaries(0);
+        }
+    }
+
     fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut out = Vec::new();
+        self.visit_in_radius(sample_point, radius, &mut |e, _| out.push(e));
+        out
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        self.visit_in_radius_on_layers(sample_point, radius, SpatialLayers::ALL, visit);
+    }
+
+    fn visit_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
         if !self.built || self.nodes.is_empty() {
-            return Vec::new();
+            return;
         }
 
-        let mut out = Vec::new();
         let mut stack = Vec::new();
         stack.push(0usize);
 
         while let Some(idx) = stack.pop() {
             let n = &self.nodes[idx];
-            if !n.bounds.intersects_sphere(sample_point, radius) {
+            // Entities may sit up to `loose_padding` outside their leaf, see `update_internal`.
+            if !n.layers.matches(mask)
+                || !n
+                    .bounds
+                    .intersects_sphere(sample_point, radius, self.cfg.loose_padding)
+            {
                 continue;
             }
 
@@ -353,17 +940,326 @@ impl SpatialLookupAlgorithm for Octree {
                 }
             } else {
                 // leaf: exact distance check to satisfy trait contract
-                for &(e, p) in &n.bucket {
+                for (e, p) in n.bucket_on_layers(mask) {
                     if p.distance(sample_point) <= radius {
-                        out.push(e);
+                        visit(e, p);
                     }
                 }
             }
         }
+    }
+
+    fn count_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.count_in_shape(&SphereQuery {
+            center: sample_point,
+            radius,
+        })
+    }
+
+    fn estimate_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.estimate_in_sphere(sample_point, radius).round() as usize
+    }
+
+    fn entities_in_radius_with_distance(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> Vec<(Entity, Vec3, f32)> {
+        let sphere = SphereQuery {
+            center: sample_point,
+            radius,
+        };
+
+        let mut out = Vec::new();
+        self.visit_shape(&sphere, SpatialLayers::ALL, &mut |e, p| {
+            out.push((e, p, p.distance_squared(sample_point)));
+        });
+        out
+    }
+
+    fn entities_in_aabb_on_layers(&self, min: Vec3, max: Vec3, mask: SpatialLayers) -> Vec<Entity> {
+        self.entities_in_shape(&AabbQuery { min, max }, mask)
+    }
+
+    fn entities_along_ray_on_layers(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        let segment = SegmentQuery {
+            origin,
+            direction: *direction,
+            max_t,
+            thickness,
+        };
+        if segment.is_empty() {
+            return Vec::new();
+        }
 
+        let mut out = Vec::new();
+        self.visit_shape(&segment, mask, &mut |e, p| {
+            if let Some(t) = segment.hit(p) {
+                out.push((e, t));
+            }
+        });
+        out.sort_by_key(|(e, t)| (FloatOrd(*t), *e));
         out
     }
 
+    fn entities_in_frustum_on_layers(&self, frustum: &Frustum, mask: SpatialLayers) -> Vec<Entity> {
+        self.entities_in_shape(&FrustumQuery { frustum }, mask)
+    }
+
+    fn entities_in_cone_on_layers(
+        &self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let cone = ConeQuery {
+            apex,
+            direction: *direction,
+            half_angle,
+            range,
+        };
+
+        self.entities_in_shape(&cone, mask)
+    }
+
+    fn entities_in_capsule_on_layers(
+        &self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let capsule = SegmentQuery::between(a, b, radius);
+        if capsule.is_empty() {
+            return Vec::new();
+        }
+
+        self.entities_in_shape(&capsule, mask)
+    }
+
+    fn entities_in_obb_on_layers(
+        &self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let obb = ObbQuery {
+            center,
+            half_extents,
+            rotation,
+        };
+
+        self.entities_in_shape(&obb, mask)
+    }
+
+    fn entities_in_cylinder_on_layers(
+        &self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let cylinder = CylinderQuery {
+            center,
+            axis: *axis,
+            radius,
+            height_range,
+        };
+        if cylinder.is_empty() {
+            return Vec::new();
+        }
+
+        self.entities_in_shape(&cylinder, mask)
+    }
+
+    fn entities_in_shell_on_layers(
+        &self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let shell = ShellQuery {
+            center,
+            min_radius,
+            max_radius,
+        };
+
+        self.entities_in_shape(&shell, mask)
+    }
+
+    fn entities_overlapping_sphere_on_layers(
+        &self,
+        center: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let mut out = Vec::new();
+        self.visit_overlapping(&SphereQuery { center, radius }, mask, &mut |e| out.push(e));
+        out
+    }
+
+    fn entities_overlapping_aabb_on_layers(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let mut out = Vec::new();
+        self.visit_overlapping(&AabbQuery { min, max }, mask, &mut |e| out.push(e));
+        out
+    }
+
+    fn visit_pairs_within_on_layers(
+        &self,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        if !self.built || self.nodes.is_empty() {
+            return;
+        }
+
+        self.visit_pairs_in(0, distance, mask, visit);
+    }
+
+    fn visit_pairs_between_on_layers(
+        &self,
+        other: &dyn SpatialLookupAlgorithm,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        let Some(other) = other
+            .as_any()
+            .and_then(|other| other.downcast_ref::<Octree>())
+        else {
+            crate::visit_pairs_between_by_lookup(self, other, distance, mask, visit);
+            return;
+        };
+
+        if distance >= 0.0
+            && self.built
+            && !self.nodes.is_empty()
+            && other.built
+            && !other.nodes.is_empty()
+        {
+            self.visit_pairs_between(0, other, 0, distance, mask, visit);
+        }
+    }
+
+    fn nearest_k_on_layers(
+        &self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Option<Entity>,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        if !self.built || self.nodes.is_empty() {
+            return Vec::new();
+        }
+
+        let mut nearest = KNearest::new(sample_point, k, max_distance, exclude);
+        let mut queue = BinaryHeap::new();
+        queue.push(Closest {
+            distance_squared: self.node_distance_squared(0, sample_point),
+            item: 0usize,
+        });
+
+        while let Some(Closest {
+            distance_squared,
+            item: idx,
+        }) = queue.pop()
+        {
+            if distance_squared > nearest.bound_squared() {
+                break;
+            }
+
+            let n = &self.nodes[idx];
+            if let Some(children) = n.children {
+                for c in children {
+                    if self.nodes[c].layers.matches(mask) {
+                        queue.push(Closest {
+                            distance_squared: self.node_distance_squared(c, sample_point),
+                            item: c,
+                        });
+                    }
+                }
+            } else {
+                for (e, p) in n.bucket_on_layers(mask) {
+                    nearest.offer(e, p);
+                }
+            }
+        }
+
+        nearest.into_sorted_vec()
+    }
+
+    fn nearest_iter_on_layers(
+        &self,
+        sample_point: Vec3,
+        mask: SpatialLayers,
+    ) -> Box<dyn Iterator<Item = (Entity, f32)> + '_> {
+        let mut queue = CandidateQueue::new();
+        if self.built && !self.nodes.is_empty() {
+            queue.push(Closest {
+                distance_squared: self.node_distance_squared(0, sample_point),
+                item: Candidate::Node(0usize),
+            });
+        }
+
+        Box::new(NearestIter::new(
+            queue,
+            move |idx: usize, queue: &mut CandidateQueue<usize>| {
+                let n = &self.nodes[idx];
+                if let Some(children) = n.children {
+                    for c in children {
+                        if self.nodes[c].layers.matches(mask) {
+                            queue.push(Closest {
+                                distance_squared: self.node_distance_squared(c, sample_point),
+                                item: Candidate::Node(c),
+                            });
+                        }
+                    }
+                } else {
+                    queue.extend(n.bucket_on_layers(mask).map(|(e, p)| Closest {
+                        distance_squared: p.distance_squared(sample_point),
+                        item: Candidate::Entity(e),
+                    }));
+                }
+            },
+        ))
+    }
+
+    fn supports_layers(&self) -> bool {
+        true
+    }
+
+    fn reports_positions(&self) -> bool {
+        true
+    }
+
+    fn returns_unique_entities(&self) -> Option<UniqueEntities> {
+        // SAFETY: every lookup of `Octree` visits each entity at most once.
+        Some(unsafe { UniqueEntities::new() })
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+
     fn supports_incremental(&self) -> bool {
         true
     }
@@ -374,7 +1270,7 @@ impl SpatialLookupAlgorithm for Octree {
             self.build_from_entities(&[(entity, position)]);
             return;
         }
-        self.insert_internal(entity, position);
+        self.insert_internal(entity, position, SpatialLayers::DEFAULT);
     }
 
     fn remove_entity(&mut self, entity: Entity) {
@@ -382,6 +1278,7 @@ impl SpatialLookupAlgorithm for Octree {
             return;
         }
         self.remove_internal(entity);
+        self.entity_extent.remove(&entity);
     }
 
     fn update_entity(&mut self, entity: Entity, position: Vec3) {
@@ -392,6 +1289,20 @@ impl SpatialLookupAlgorithm for Octree {
         self.update_internal(entity, position);
     }
 
+    fn insert_entity_with_extent(&mut self, entity: Entity, position: Vec3, extent: f32) {
+        self.insert_entity(entity, position);
+        self.set_extent(entity, extent);
+    }
+
+    fn update_entity_with_extent(&mut self, entity: Entity, position: Vec3, extent: f32) {
+        self.update_entity(entity, position);
+        self.set_extent(entity, extent);
+    }
+
+    fn set_entity_layers(&mut self, entity: Entity, layers: SpatialLayers) {
+        self.set_layers(entity, layers);
+    }
+
     fn debug_gizmos(&self, gizmos: &mut Gizmos) {
         if !self.built {
             return;
@@ -399,7 +1310,10 @@ impl SpatialLookupAlgorithm for Octree {
         for n in &self.nodes {
             // draw node bounds as wire cube
             let s = n.bounds.half * 2.0;
-            gizmos.cube(Transform::from_translation(n.bounds.center).with_scale(Vec3::splat(s)), Color::WHITE);
+            gizmos.cube(
+                Transform::from_translation(n.bounds.center).with_scale(Vec3::splat(s)),
+                Color::WHITE,
+            );
         }
     }
 }
@@ -0,0 +1,131 @@
+use bevy::prelude::{Entity, Vec3};
+use bevy::tasks::ComputeTaskPool;
+use std::ops::Index;
+
+/// Results of many spatial lookups done together, in compressed sparse row layout.
+///
+/// The entities found by all lookups are stored back to back in a single list, and the results of
+/// lookup `i` are `entities()[offsets()[i]..offsets()[i + 1]]`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BatchResults {
+    offsets: Vec<usize>,
+    entities: Vec<Entity>,
+}
+
+impl BatchResults {
+    /// Returns the number of lookups in the batch.
+    pub fn len(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+
+    /// Returns true if the batch contains no lookups.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the entities found by lookup `i`, or `None` if `i` is out of bounds.
+    pub fn get(&self, i: usize) -> Option<&[Entity]> {
+        let start = *self.offsets.get(i)?;
+        let end = *self.offsets.get(i + 1)?;
+
+        Some(&self.entities[start..end])
+    }
+
+    /// Iterates over the entities found by each lookup, in the order the lookups were given.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &[Entity]> + '_ {
+        self.offsets
+            .windows(2)
+            .map(|range| &self.entities[range[0]..range[1]])
+    }
+
+    /// Offsets into `entities` at which the results of each lookup start, followed by the total
+    /// number of entities.
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// The entities found by all lookups, back to back.
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    /// Removes every entity for which `keep` returns false, keeping the layout compact.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(Entity) -> bool) {
+        let mut kept = 0;
+        let mut start = 0;
+
+        for i in 0..self.len() {
+            let end = self.offsets[i + 1];
+            for j in start..end {
+                if keep(self.entities[j]) {
+                    self.entities[kept] = self.entities[j];
+                    kept += 1;
+                }
+            }
+
+            start = end;
+            self.offsets[i + 1] = kept;
+        }
+
+        self.entities.truncate(kept);
+    }
+}
+
+impl Index<usize> for BatchResults {
+    type Output = [Entity];
+
+    fn index(&self, i: usize) -> &[Entity] {
+        &self.entities[self.offsets[i]..self.offsets[i + 1]]
+    }
+}
+
+/// Runs a radius lookup with `visit_in_radius` for each `(sample_point, radius)` pair, splitting
+/// the lookups across the `ComputeTaskPool` if it has been initialized.
+///
+/// Each task collects the results of its share of the lookups into a single buffer, so the number
+/// of allocations depends on the number of threads rather than the number of lookups.
+pub(crate) fn batch_in_radius(
+    queries: &[(Vec3, f32)],
+    visit_in_radius: impl Fn(Vec3, f32, &mut dyn FnMut(Entity, Vec3)) + Sync,
+) -> BatchResults {
+    let run_chunk = |chunk: &[(Vec3, f32)]| {
+        let mut lens = Vec::with_capacity(chunk.len());
+        let mut found = Vec::new();
+
+        for &(sample_point, radius) in chunk {
+            let before = found.len();
+            visit_in_radius(sample_point, radius, &mut |entity, _| found.push(entity));
+            lens.push(found.len() - before);
+        }
+
+        (lens, found)
+    };
+
+    let chunks = match ComputeTaskPool::try_get() {
+        Some(task_pool) if task_pool.thread_num() > 1 && queries.len() > 1 => {
+            let chunk_size = queries.len().div_ceil(task_pool.thread_num());
+            task_pool.scope(|scope| {
+                for chunk in queries.chunks(chunk_size) {
+                    scope.spawn(async move { run_chunk(chunk) });
+                }
+            })
+        }
+        _ => vec![run_chunk(queries)],
+    };
+
+    let mut results = BatchResults {
+        offsets: Vec::with_capacity(queries.len() + 1),
+        entities: Vec::with_capacity(chunks.iter().map(|(_, found)| found.len()).sum()),
+    };
+    results.offsets.push(0);
+
+    for (lens, found) in chunks {
+        for len in lens {
+            let end = results.offsets.last().copied().unwrap_or_default() + len;
+            results.offsets.push(end);
+        }
+        results.entities.extend(found);
+    }
+
+    results
+}
@@ -0,0 +1,23 @@
+use bevy::prelude::Entity;
+use std::error::Error;
+use std::fmt;
+
+/// Errors returned by spatial queries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpatialQueryError {
+    /// The entity is not tracked by the `SpatialLookupState`, usually because it has no
+    /// `SpatialQueryEntity` component or hasn't been picked up by `PrepareSpatialLookup` yet.
+    NotIndexed(Entity),
+}
+
+impl fmt::Display for SpatialQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpatialQueryError::NotIndexed(entity) => {
+                write!(f, "entity {entity} is not indexed by the spatial lookup")
+            }
+        }
+    }
+}
+
+impl Error for SpatialQueryError {}
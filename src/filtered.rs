@@ -0,0 +1,279 @@
+use crate::{
+    DefaultSpatialIndex, PrepareSpatialLookup, SpatialExtent, SpatialIndex, SpatialLayers,
+    SpatialLookupAlgorithm, SpatialLookupState, SpatialQueryEntity,
+};
+use bevy::ecs::component::ComponentId;
+use bevy::ecs::event::EntityEvent;
+use bevy::ecs::query::{ArchetypeFilter, QueryFilter};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use std::any::TypeId;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// Maintains a sub-index of the entities of index `I` which match the filter `F`.
+///
+/// A `SpatialQuery` whose filter is exactly `F` then looks up entities in the sub-index instead of
+/// in the whole index, so entities it would discard are never visited:
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_mod_spatial_query::prelude::*;
+/// #[derive(Component)]
+/// struct Enemy;
+///
+/// # let mut app = App::new();
+/// app.add_plugins((SpatialQueriesPlugin, SpatialFilterPlugin::<With<Enemy>>::default()));
+///
+/// fn damage_nearby_enemies(enemies: SpatialQuery<&mut Transform, With<Enemy>>) {
+///     // ...
+/// }
+/// ```
+///
+/// The sub-index is updated along with index `I`, taking the positions, extents and layers from
+/// it: entities which are added to or removed from index `I`, or which gain or lose a component `F`
+/// filters on, are synced right away, and moved entities once index `I` picks them up in
+/// `FixedLast`. Entries of index `I` changed directly through its `SpatialLookupState` are synced in
+/// `PrepareSpatialLookup`.
+pub struct SpatialFilterPlugin<F, I: SpatialIndex = DefaultSpatialIndex>(
+    PhantomData<fn() -> (F, I)>,
+);
+
+impl<F, I: SpatialIndex> Default for SpatialFilterPlugin<F, I> {
+    fn default() -> Self {
+        SpatialFilterPlugin(PhantomData)
+    }
+}
+
+impl<F, I> Plugin for SpatialFilterPlugin<F, I>
+where
+    F: QueryFilter + ArchetypeFilter + 'static,
+    I: SpatialIndex,
+{
+    fn build(&self, app: &mut App) {
+        // Keep a `FilteredLookupState<F, I>` inserted before the plugin, so the sub-index can be
+        // given its own algorithm.
+        app.init_resource::<FilteredLookupState<F, I>>()
+            .add_systems(
+                First,
+                prepare_filtered_lookup::<F, I>
+                    .in_set(PrepareSpatialLookup)
+                    .in_set(SyncFilteredLookup),
+            )
+            .add_systems(
+                FixedLast,
+                sync_filtered_lookup::<F, I>.in_set(SyncFilteredLookup),
+            )
+            // Follow the observers which add and remove entries of index `I`
+            .add_observer(index_entry_changed::<Add, SpatialQueryEntity<I>, F, I>)
+            .add_observer(index_entry_changed::<Remove, SpatialQueryEntity<I>, F, I>)
+            .add_observer(index_entry_changed::<Remove, SpatialExtent, F, I>)
+            .add_observer(index_entry_changed::<Remove, SpatialLayers, F, I>);
+
+        // Entities which gain or lose a component `F` filters on may start or stop matching it.
+        let filter = QueryState::<(), F>::new(app.world_mut());
+        let access = filter.component_access();
+        let mut components: Vec<ComponentId> = access
+            .with_filters()
+            .chain(access.without_filters())
+            .collect();
+        components.sort();
+        components.dedup();
+
+        if !components.is_empty() {
+            app.world_mut().spawn_batch([
+                Observer::new(filter_components_changed::<Add, F, I>)
+                    .with_components(components.clone()),
+                Observer::new(filter_components_changed::<Remove, F, I>)
+                    .with_components(components),
+            ]);
+        }
+    }
+}
+
+/// Runs after the indices have been updated, so sub-indices see their current contents.
+#[derive(SystemSet, Clone, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct SyncFilteredLookup;
+
+/// Resource which holds the sub-index of the entities of index `I` matching the filter `F`, see
+/// `SpatialFilterPlugin`.
+#[derive(Resource)]
+pub struct FilteredLookupState<F: 'static, I: SpatialIndex = DefaultSpatialIndex> {
+    state: SpatialLookupState<I>,
+    /// Whether the sub-index was synced with every entity matching `F` once.
+    synced: bool,
+    /// Entities which may have started or stopped matching `F`, or whose entry in index `I` changed,
+    /// since the last sync.
+    unsynced: HashSet<Entity>,
+    filter: PhantomData<fn() -> F>,
+}
+
+impl<F: 'static, I: SpatialIndex> Default for FilteredLookupState<F, I> {
+    fn default() -> Self {
+        Self {
+            state: SpatialLookupState::default(),
+            synced: false,
+            unsynced: HashSet::default(),
+            filter: PhantomData,
+        }
+    }
+}
+
+impl<F: 'static, I: SpatialIndex> FilteredLookupState<F, I> {
+    /// Creates the sub-index, using the given algorithm.
+    pub fn new<T: SpatialLookupAlgorithm + Send + Sync + 'static>(algorithm: T) -> Self {
+        Self {
+            state: SpatialLookupState::new(algorithm),
+            synced: false,
+            unsynced: HashSet::default(),
+            filter: PhantomData,
+        }
+    }
+
+    /// Adds, updates or removes `entity` so its entry matches the one in `index`, if it matches
+    /// `F`.
+    fn sync_entity(&mut self, entity: Entity, matches: bool, index: &SpatialLookupState<I>) {
+        // Not indexed (yet), e.g. because it has no position
+        let Some(position) = index.position_of(entity).filter(|_| matches) else {
+            self.state.remove_entity(entity);
+            return;
+        };
+
+        let extent = index.extent_of(entity).unwrap_or_default();
+        let moved = self.position_of(entity) != Some(position);
+        if moved || self.extent_of(entity) != Some(extent) {
+            self.upsert_entity_with_extent(entity, position, extent);
+        }
+
+        let layers = index.layers_of(entity).unwrap_or_default();
+        if self.layers_of(entity) != Some(layers) {
+            self.set_layers(entity, layers);
+        }
+    }
+}
+
+impl<F: 'static, I: SpatialIndex> Deref for FilteredLookupState<F, I> {
+    type Target = SpatialLookupState<I>;
+
+    fn deref(&self) -> &SpatialLookupState<I> {
+        &self.state
+    }
+}
+
+impl<F: 'static, I: SpatialIndex> DerefMut for FilteredLookupState<F, I> {
+    fn deref_mut(&mut self) -> &mut SpatialLookupState<I> {
+        &mut self.state
+    }
+}
+
+/// Syncs the sub-index with the entities of index `I` matching `F`, then prepares its algorithm.
+pub fn prepare_filtered_lookup<F: QueryFilter + ArchetypeFilter + 'static, I: SpatialIndex>(
+    matching: Query<Entity, (With<SpatialQueryEntity<I>>, F)>,
+    mut index: ResMut<SpatialLookupState<I>>,
+    mut filtered: ResMut<FilteredLookupState<F, I>>,
+) {
+    sync_filtered(&matching, &mut index, &mut filtered);
+    filtered.prepare_algorithm();
+}
+
+/// Syncs the sub-index with the entities of index `I` matching `F`.
+fn sync_filtered_lookup<F: QueryFilter + ArchetypeFilter + 'static, I: SpatialIndex>(
+    matching: Query<Entity, (With<SpatialQueryEntity<I>>, F)>,
+    mut index: ResMut<SpatialLookupState<I>>,
+    mut filtered: ResMut<FilteredLookupState<F, I>>,
+) {
+    sync_filtered(&matching, &mut index, &mut filtered);
+}
+
+/// Only entities whose entry in index `I` changed since the last sync, or which gained or lost a
+/// component `F` filters on, are synced. Every matching entity is synced once, and again if either
+/// state was replaced.
+fn sync_filtered<F: QueryFilter + ArchetypeFilter + 'static, I: SpatialIndex>(
+    matching: &Query<Entity, (With<SpatialQueryEntity<I>>, F)>,
+    index: &mut SpatialLookupState<I>,
+    filtered: &mut FilteredLookupState<F, I>,
+) {
+    let sub_index = TypeId::of::<FilteredLookupState<F, I>>();
+    let recorded = index.take_changes(sub_index, &mut filtered.unsynced);
+
+    if recorded && filtered.synced {
+        let mut unsynced = std::mem::take(&mut filtered.unsynced);
+        for entity in unsynced.drain() {
+            filtered.sync_entity(entity, matching.contains(entity), index);
+        }
+        // keep the allocation for the next sync
+        filtered.unsynced = unsynced;
+    } else {
+        filtered.unsynced.clear();
+        for entity in matching {
+            filtered.sync_entity(entity, true, index);
+        }
+
+        // Every matching entity is tracked now, so anything else no longer matches.
+        let stale: Vec<Entity> = filtered
+            .entities
+            .iter()
+            .map(|&(entity, _)| entity)
+            .filter(|&entity| !matching.contains(entity) || index.position_of(entity).is_none())
+            .collect();
+        for entity in stale {
+            filtered.remove_entity(entity);
+        }
+        filtered.synced = true;
+    }
+}
+
+/// Observer: when a component `F` filters on is added or removed, the entity has to be synced with
+/// the sub-index, as it may have started or stopped matching `F`.
+///
+/// The sync is deferred until the component was actually removed.
+fn filter_components_changed<E, F, I>(
+    trigger: On<E>,
+    mut filtered: ResMut<FilteredLookupState<F, I>>,
+    mut commands: Commands,
+) where
+    E: EntityEvent,
+    F: QueryFilter + ArchetypeFilter + 'static,
+    I: SpatialIndex,
+{
+    filtered.unsynced.insert(trigger.event_target());
+    commands.run_system_cached(sync_filtered_lookup::<F, I>);
+}
+
+/// Observer: when index `I` adds, updates or removes an entry, sync the entity with the sub-index
+/// once the observers of index `I` ran.
+fn index_entry_changed<E, B, F, I>(_trigger: On<E, B>, mut commands: Commands)
+where
+    E: EntityEvent,
+    B: Bundle,
+    F: QueryFilter + ArchetypeFilter + 'static,
+    I: SpatialIndex,
+{
+    commands.run_system_cached(sync_filtered_lookup::<F, I>);
+}
+
+/// The lookup state a `SpatialQuery` consults: the sub-index registered for its filter `F` if
+/// there is one, otherwise the whole index `I`.
+#[derive(SystemParam)]
+pub(crate) struct IndexLookup<'w, F: 'static, I: SpatialIndex> {
+    index: Res<'w, SpatialLookupState<I>>,
+    filtered: Option<Res<'w, FilteredLookupState<F, I>>>,
+}
+
+impl<F: 'static, I: SpatialIndex> IndexLookup<'_, F, I> {
+    /// The whole index, regardless of any sub-index.
+    pub(crate) fn index(&self) -> &SpatialLookupState<I> {
+        &self.index
+    }
+}
+
+impl<F: 'static, I: SpatialIndex> Deref for IndexLookup<'_, F, I> {
+    type Target = SpatialLookupState<I>;
+
+    fn deref(&self) -> &SpatialLookupState<I> {
+        match &self.filtered {
+            Some(filtered) => filtered,
+            None => &self.index,
+        }
+    }
+}
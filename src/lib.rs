@@ -49,24 +49,118 @@
 //! app.insert_resource(SpatialLookupState::with_algorithm(YourAwesomeAlgorithm));
 //! ```
 //!
+use algorithms::geometry::{
+    AabbQuery, ConeQuery, CylinderQuery, FrustumQuery, ObbQuery, QueryShape, SegmentQuery,
+    ShellQuery,
+};
+use algorithms::nearest::{Candidate, CandidateQueue, Closest, KNearest, NearestIter};
+use bevy::camera::primitives::Frustum;
+use bevy::math::FloatOrd;
 use bevy::prelude::*;
-use std::collections::HashMap;
+use std::any::{Any, TypeId};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ops::Deref;
 
 pub mod algorithms;
+mod batch;
+mod error;
+mod filtered;
 mod spatial_query;
 mod spatial_query_iterator;
 
 pub mod prelude {
-    pub use crate::spatial_query::SpatialQuery;
+    pub use crate::algorithms::{Bvh, Naive, Octree, OctreeConfig};
+    pub use crate::batch::BatchResults;
+    pub use crate::error::SpatialQueryError;
+    pub use crate::filtered::{FilteredLookupState, SpatialFilterPlugin};
+    pub use crate::spatial_query::NearestResults;
     pub use crate::spatial_query::ReadOnlySpatialQuery;
+    pub use crate::spatial_query::SpatialQuery;
+    pub use crate::spatial_query_iterator::PooledEntities;
     pub use crate::spatial_query_iterator::SpatialQueryIterator;
     pub use crate::spatial_query_iterator::SpatialQueryIteratorRo;
-    pub use crate::{SpatialLookupAlgorithm, SpatialLookupState, SpatialQueriesPlugin, SpatialQueryEntity, PrepareSpatialLookup};
-    pub use crate::algorithms::{Naive, Bvh, Octree, OctreeConfig};
+    pub use crate::spatial_query_iterator::SpatialQueryResult;
+    pub use crate::{DefaultSpatialIndex, SpatialIndex, SpatialPosition};
+    pub use crate::{
+        PrepareSpatialLookup, SpatialExtent, SpatialLayers, SpatialLookupAlgorithm,
+        SpatialLookupState, SpatialQueriesPlugin, SpatialQueryEntity, UniqueEntities,
+    };
 }
 
 /// Adds `SpatialQuery` support to bevy.
-pub struct SpatialQueriesPlugin;
+///
+/// Each index `I` is a separate plugin with its own `SpatialLookupState<I>`, tracking the entities
+/// marked with `SpatialQueryEntity<I>`:
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_mod_spatial_query::prelude::*;
+/// struct Enemies;
+///
+/// # let mut app = App::new();
+/// app.add_plugins((SpatialQueriesPlugin, SpatialQueriesPlugin::<Enemies>::default()));
+/// # app.world_mut().spawn((Transform::default(), SpatialQueryEntity::<Enemies>::default()));
+/// ```
+///
+/// Entities are indexed at the position of their `P` component, see `SpatialPosition`.
+pub struct SpatialQueriesPlugin<
+    I: SpatialIndex = DefaultSpatialIndex,
+    P: SpatialPosition = GlobalTransform,
+> {
+    index: PhantomData<(I, P)>,
+}
+
+/// The plugin for the default index, so that `app.add_plugins(SpatialQueriesPlugin)` keeps
+/// working like it did before indices were keyed by a marker type.
+#[allow(non_upper_case_globals)]
+pub const SpatialQueriesPlugin: SpatialQueriesPlugin = SpatialQueriesPlugin::new();
+
+impl SpatialQueriesPlugin {
+    /// Creates the plugin for the default index.
+    pub const fn new() -> Self {
+        SpatialQueriesPlugin { index: PhantomData }
+    }
+}
+
+impl<I: SpatialIndex, P: SpatialPosition> Default for SpatialQueriesPlugin<I, P> {
+    fn default() -> Self {
+        SpatialQueriesPlugin { index: PhantomData }
+    }
+}
+
+/// Marker type which keys a spatial index, see `SpatialQueriesPlugin`.
+///
+/// Implemented for every `Send + Sync + 'static` type, so any type can be used as a key.
+pub trait SpatialIndex: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> SpatialIndex for T {}
+
+/// Component which an index reads the position of its entities from.
+///
+/// Implemented for `GlobalTransform`, which is used by default, and `Transform`. Implement it for
+/// your own component to index entities which have no transform, and pick it with
+/// `SpatialQueriesPlugin::<I, P>`. The index is updated whenever the component changes.
+pub trait SpatialPosition: Component {
+    /// The position to index the entity at.
+    fn position(&self) -> Vec3;
+}
+
+impl SpatialPosition for GlobalTransform {
+    fn position(&self) -> Vec3 {
+        self.translation()
+    }
+}
+
+impl SpatialPosition for Transform {
+    fn position(&self) -> Vec3 {
+        self.translation
+    }
+}
+
+/// The index used when no index is specified.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultSpatialIndex;
 
 /// System set for systems used to set up the spatial lookup.
 ///
@@ -77,22 +171,713 @@ pub struct SpatialQueriesPlugin;
 #[derive(SystemSet, Clone, Debug, Hash, PartialEq, Eq)]
 pub struct PrepareSpatialLookup;
 
-#[derive(Component, Clone)]
-pub struct SpatialQueryEntity;
+/// Marks an entity to be tracked by the spatial index `I`.
+#[derive(Component)]
+pub struct SpatialQueryEntity<I: SpatialIndex = DefaultSpatialIndex> {
+    index: PhantomData<I>,
+}
+
+/// The marker for the default index, so that e.g. `commands.spawn((transform, SpatialQueryEntity))`
+/// keeps working like it did before indices were keyed by a marker type.
+#[allow(non_upper_case_globals)]
+pub const SpatialQueryEntity: SpatialQueryEntity = SpatialQueryEntity::new();
+
+impl SpatialQueryEntity {
+    /// Creates the marker for the default index.
+    pub const fn new() -> Self {
+        SpatialQueryEntity { index: PhantomData }
+    }
+}
+
+impl<I: SpatialIndex> Default for SpatialQueryEntity<I> {
+    fn default() -> Self {
+        SpatialQueryEntity { index: PhantomData }
+    }
+}
+
+impl<I: SpatialIndex> Clone for SpatialQueryEntity<I> {
+    fn clone(&self) -> Self {
+        SpatialQueryEntity { index: PhantomData }
+    }
+}
+
+/// Radius of a bounding sphere around a `SpatialQueryEntity`'s position.
+///
+/// Entities without this component are indexed as points. The extent is only taken into account by
+/// overlap queries like `SpatialQuery::overlapping_sphere`, all other queries test the position.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct SpatialExtent(pub f32);
+
+/// Bitmask of the layers a `SpatialQueryEntity` is on, stored in the index next to its position.
+///
+/// Queries like `SpatialQuery::in_radius_on_layers` take a mask of the same type, and the lookup
+/// algorithm skips entities which share no layer with it while walking the index, so e.g.
+/// `nearest_k_on_layers` still finds `k` entities if there are enough on the mask. A mask of
+/// `SpatialLayers::ALL` finds every entity, including those on no layer. Entities without this
+/// component are on `SpatialLayers::DEFAULT`.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SpatialLayers(pub u32);
+
+impl SpatialLayers {
+    /// The first layer only.
+    pub const DEFAULT: Self = SpatialLayers(1);
+    /// Every layer.
+    pub const ALL: Self = SpatialLayers(u32::MAX);
+    /// No layers.
+    pub const NONE: Self = SpatialLayers(0);
+
+    /// Returns true if `self` and `other` share at least one layer.
+    pub const fn intersects(self, other: SpatialLayers) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Returns true if an entity on `self` is found by lookups on `mask`: if they share a layer,
+    /// or if `mask` is `SpatialLayers::ALL`.
+    ///
+    /// Lookup algorithms which index layers, see `SpatialLookupAlgorithm::supports_layers`, test
+    /// entities with this. It also holds for the union of the layers under a tree node, so whole
+    /// nodes can be skipped.
+    pub const fn matches(self, mask: SpatialLayers) -> bool {
+        mask.0 == u32::MAX || self.intersects(mask)
+    }
+}
+
+impl Default for SpatialLayers {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Promise of a `SpatialLookupAlgorithm` that every lookup returns each entity at most once, see
+/// `SpatialLookupAlgorithm::returns_unique_entities`.
+#[derive(Clone, Copy, Debug)]
+pub struct UniqueEntities(());
+
+impl UniqueEntities {
+    /// Creates the promise.
+    ///
+    /// # Safety
+    ///
+    /// Every lookup of the algorithm returning this, including the default implementations it
+    /// relies on, *MUST* return each entity at most once. `SpatialQuery` hands out mutable items
+    /// for the returned entities without checking for duplicates, so a duplicate would alias them.
+    pub const unsafe fn new() -> Self {
+        UniqueEntities(())
+    }
+}
 
 /// Trait for defining Spatial Lookup Algorithms to be used with `SpatialQuery<_>`.
+///
+/// Every lookup *MUST* return each entity at most once. `SpatialQuery` hands out mutable items
+/// for the returned entities, so it guards against duplicates by skipping repeated entities, but
+/// a duplicate still costs a wasted lookup and is a bug in the algorithm. Algorithms which can
+/// guarantee unique results skip that guard, see `returns_unique_entities`.
+///
+/// Only `prepare` and `entities_in_radius` are required. Algorithms which don't report the
+/// positions of the entities they return, see `reports_positions`, get every other lookup built on
+/// top of `entities_in_radius` by the `SpatialLookupState`.
 pub trait SpatialLookupAlgorithm {
     /// Prepares the lookup algorithm with a fresh set of entities and their positions.
     ///
     /// Called when the algorithm is (re)initialized or when a full rebuild is requested.
     fn prepare(&mut self, entities: &[(Entity, Vec3)]);
 
+    /// Same as `prepare`, but also receives the bounding sphere radius of each entity, in the same
+    /// order as `entities`.
+    ///
+    /// Algorithms which index extents should override this together with the overlap queries. The
+    /// default implementation ignores the extents.
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], _extents: &[f32]) {
+        self.prepare(entities);
+    }
+
+    /// Same as `prepare_with_extents`, but also receives the layers of each entity, in the same
+    /// order as `entities`.
+    ///
+    /// Algorithms which index layers should override this, see `supports_layers`. The default
+    /// implementation ignores the layers.
+    fn prepare_with_layers(
+        &mut self,
+        entities: &[(Entity, Vec3)],
+        extents: &[f32],
+        _layers: &[SpatialLayers],
+    ) {
+        self.prepare_with_extents(entities, extents);
+    }
+
     /// Returns a list of all entities that are within the given radius of the sample point.
     ///
     /// This method *MUST* return all entities within the radius of the sample point, and it *MUST*
     /// not return any entities outside of it.
     fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity>;
 
+    /// Calls `visit` with every entity within the given radius of the sample point, together with
+    /// its indexed position.
+    ///
+    /// This is the allocation-free counterpart of `entities_in_radius` and *MUST* visit exactly
+    /// the same entities. The default implementation collects `entities_in_radius_with_distance`,
+    /// so algorithms should override it to avoid that allocation. Algorithms which report
+    /// positions *MUST* override at least one of the two, as their defaults call each other.
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        for (entity, position, _) in self.entities_in_radius_with_distance(sample_point, radius) {
+            visit(entity, position);
+        }
+    }
+
+    /// Same as `visit_in_radius`, but only visits entities whose layers match `mask`, see
+    /// `SpatialLayers::matches`.
+    ///
+    /// The other `_on_layers` lookups are built on this by default, so algorithms which index
+    /// layers need to override at least this one. The default implementation ignores the mask,
+    /// which is fine because `SpatialLookupState` only calls the `_on_layers` lookups if
+    /// `supports_layers` returns true.
+    fn visit_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        _mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        self.visit_in_radius(sample_point, radius, visit);
+    }
+
+    /// Same as `entities_in_radius`, but returns each entity together with its indexed position
+    /// and its squared distance to the sample point.
+    ///
+    /// This method *MUST* return exactly the same entities as `entities_in_radius`. The default
+    /// implementation collects the entities passed to `visit_in_radius`.
+    fn entities_in_radius_with_distance(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> Vec<(Entity, Vec3, f32)> {
+        let mut found = Vec::new();
+        self.visit_in_radius(sample_point, radius, &mut |entity, position| {
+            found.push((entity, position, position.distance_squared(sample_point)));
+        });
+
+        found
+    }
+
+    /// Returns the number of entities within the given radius of the sample point.
+    ///
+    /// This method *MUST* return exactly the number of entities `entities_in_radius` returns. The
+    /// default implementation counts the entities passed to `visit_in_radius`.
+    fn count_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        let mut count = 0;
+        self.visit_in_radius(sample_point, radius, &mut |_, _| count += 1);
+
+        count
+    }
+
+    /// Returns an approximate number of entities within the given radius of the sample point.
+    ///
+    /// Unlike `count_in_radius`, the result may be off in either direction, in exchange for not
+    /// testing individual entities. The default implementation returns the exact count.
+    fn estimate_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.count_in_radius(sample_point, radius)
+    }
+
+    /// Returns a list of all entities inside the axis-aligned box spanned by `min` and `max`.
+    ///
+    /// The box is inclusive, so entities lying exactly on its faces are returned. This method
+    /// *MUST* return all entities inside the box, and it *MUST* not return any entities outside
+    /// of it.
+    ///
+    /// The default implementation calls `entities_in_aabb_on_layers` with `SpatialLayers::ALL`.
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Vec<Entity> {
+        self.entities_in_aabb_on_layers(min, max, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_in_aabb`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation tests the entities within the bounding sphere of the box.
+    fn entities_in_aabb_on_layers(&self, min: Vec3, max: Vec3, mask: SpatialLayers) -> Vec<Entity> {
+        let shape = AabbQuery { min, max };
+        let radius = (max - min).length() * 0.5;
+        entities_in_bounded_shape(self, (min + max) * 0.5, radius, mask, &shape)
+    }
+
+    /// Returns up to `k` entities closest to the sample point, together with their distance to
+    /// it, sorted nearest first.
+    ///
+    /// Only entities within `max_distance` (inclusive) are considered, and `exclude` is never
+    /// returned. A negative or NaN `max_distance` returns nothing. Entities at equal distances may
+    /// be returned in any order.
+    ///
+    /// The default implementation calls `nearest_k_on_layers` with `SpatialLayers::ALL`.
+    fn nearest_k(
+        &self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Option<Entity>,
+    ) -> Vec<(Entity, f32)> {
+        self.nearest_k_on_layers(sample_point, k, max_distance, exclude, SpatialLayers::ALL)
+    }
+
+    /// Same as `nearest_k`, but only considers entities whose layers match `mask`.
+    ///
+    /// Entities on other layers *MUST* be skipped before they can take one of the `k` places, so
+    /// `k` entities are returned whenever there are that many on the mask within `max_distance`.
+    ///
+    /// The default implementation keeps the `k` nearest of the entities within `max_distance`.
+    fn nearest_k_on_layers(
+        &self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Option<Entity>,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        let mut nearest = KNearest::new(sample_point, k, max_distance, exclude);
+        self.visit_in_radius_on_layers(
+            sample_point,
+            max_distance,
+            mask,
+            &mut |entity, position| nearest.offer(entity, position),
+        );
+
+        nearest.into_sorted_vec()
+    }
+
+    /// Returns an iterator over all entities and their distances to the sample point, nearest
+    /// first, without any limit on the distance.
+    ///
+    /// The iterator *SHOULD* be lazy, only doing the work needed to find the next entity when
+    /// it is advanced, so callers can stop as soon as they found what they were looking for.
+    /// Entities at equal distances may be returned in any order.
+    ///
+    /// The default implementation calls `nearest_iter_on_layers` with `SpatialLayers::ALL`.
+    fn nearest_iter(&self, sample_point: Vec3) -> Box<dyn Iterator<Item = (Entity, f32)> + '_> {
+        self.nearest_iter_on_layers(sample_point, SpatialLayers::ALL)
+    }
+
+    /// Same as `nearest_iter`, but only yields entities whose layers match `mask`.
+    ///
+    /// The default implementation is not lazy: it collects every entity up front.
+    fn nearest_iter_on_layers(
+        &self,
+        sample_point: Vec3,
+        mask: SpatialLayers,
+    ) -> Box<dyn Iterator<Item = (Entity, f32)> + '_> {
+        let mut queue = CandidateQueue::new();
+        self.visit_in_radius_on_layers(
+            sample_point,
+            f32::INFINITY,
+            mask,
+            &mut |entity, position| {
+                queue.push(Closest {
+                    distance_squared: position.distance_squared(sample_point),
+                    item: Candidate::<()>::Entity(entity),
+                });
+            },
+        );
+
+        Box::new(NearestIter::new(queue, |_, _| {}))
+    }
+
+    /// Returns all entities within `thickness` of the segment starting at `origin` and extending
+    /// `max_t` units along `direction`, together with the distance along the segment to the
+    /// point closest to each entity, sorted by that distance. Ties are ordered by `Entity`.
+    ///
+    /// `max_t` may be `f32::INFINITY` to query along a ray. Entities exactly `thickness` away
+    /// are included. A negative or NaN `max_t` or `thickness` returns nothing.
+    ///
+    /// The default implementation calls `entities_along_ray_on_layers` with `SpatialLayers::ALL`.
+    fn entities_along_ray(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+    ) -> Vec<(Entity, f32)> {
+        self.entities_along_ray_on_layers(origin, direction, max_t, thickness, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_along_ray`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation tests the entities within the bounding sphere of the segment,
+    /// or all entities for a ray.
+    fn entities_along_ray_on_layers(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        let segment = SegmentQuery {
+            origin,
+            direction: *direction,
+            max_t,
+            thickness,
+        };
+        if segment.is_empty() {
+            return Vec::new();
+        }
+
+        let (center, radius) = if max_t.is_finite() {
+            (origin + *direction * max_t * 0.5, max_t * 0.5 + thickness)
+        } else {
+            (origin, f32::INFINITY)
+        };
+
+        let mut found_entities = Vec::new();
+        self.visit_in_radius_on_layers(
+            center,
+            bounding_radius(radius),
+            mask,
+            &mut |entity, position| {
+                if let Some(t) = segment.hit(position) {
+                    found_entities.push((entity, t));
+                }
+            },
+        );
+        found_entities.sort_by_key(|(entity, t)| (FloatOrd(*t), *entity));
+
+        found_entities
+    }
+
+    /// Returns a list of all entities inside the frustum.
+    ///
+    /// Entities lying exactly on one of the frustum planes are returned. This method *MUST* return
+    /// all entities inside the frustum, and it *MUST* not return any entities outside of it.
+    ///
+    /// The default implementation calls `entities_in_frustum_on_layers` with `SpatialLayers::ALL`.
+    fn entities_in_frustum(&self, frustum: &Frustum) -> Vec<Entity> {
+        self.entities_in_frustum_on_layers(frustum, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_in_frustum`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation tests every entity.
+    fn entities_in_frustum_on_layers(&self, frustum: &Frustum, mask: SpatialLayers) -> Vec<Entity> {
+        let shape = FrustumQuery { frustum };
+        entities_in_bounded_shape(self, Vec3::ZERO, f32::INFINITY, mask, &shape)
+    }
+
+    /// Returns a list of all entities inside the cone with its apex at `apex`, opening towards
+    /// `direction` with the given half angle (in radians), and reaching `range` units from the apex.
+    ///
+    /// Like `entities_in_radius`, the test is inclusive: entities exactly `range` away from the
+    /// apex or exactly `half_angle` away from the direction are returned. This method *MUST*
+    /// return all entities inside the cone, and it *MUST* not return any entities outside of it.
+    ///
+    /// The default implementation calls `entities_in_cone_on_layers` with `SpatialLayers::ALL`.
+    fn entities_in_cone(
+        &self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+    ) -> Vec<Entity> {
+        self.entities_in_cone_on_layers(apex, direction, half_angle, range, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_in_cone`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation tests the entities within `range` of the apex.
+    fn entities_in_cone_on_layers(
+        &self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let shape = ConeQuery {
+            apex,
+            direction: *direction,
+            half_angle,
+            range,
+        };
+        entities_in_bounded_shape(self, apex, range, mask, &shape)
+    }
+
+    /// Returns a list of all entities within `radius` of the segment from `a` to `b`.
+    ///
+    /// This is the volume swept by a sphere moving from `a` to `b`, which makes it useful for
+    /// hit detection of fast-moving objects. Entities exactly `radius` away are returned, and a
+    /// negative or NaN `radius` returns nothing. This method *MUST* return all entities inside the
+    /// capsule, and it *MUST* not return any entities outside of it.
+    ///
+    /// The default implementation calls `entities_in_capsule_on_layers` with `SpatialLayers::ALL`.
+    fn entities_in_capsule(&self, a: Vec3, b: Vec3, radius: f32) -> Vec<Entity> {
+        self.entities_in_capsule_on_layers(a, b, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_in_capsule`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation tests the entities within the bounding sphere of the capsule.
+    fn entities_in_capsule_on_layers(
+        &self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let shape = SegmentQuery::between(a, b, radius);
+        if shape.is_empty() {
+            return Vec::new();
+        }
+
+        let bounds = a.distance(b) * 0.5 + radius;
+        entities_in_bounded_shape(self, (a + b) * 0.5, bounds, mask, &shape)
+    }
+
+    /// Returns a list of all entities inside the box with the given center and half extents,
+    /// rotated by `rotation` around its center.
+    ///
+    /// The box is inclusive, so entities lying exactly on its faces are returned. This method
+    /// *MUST* return all entities inside the box, and it *MUST* not return any entities outside
+    /// of it.
+    ///
+    /// The default implementation calls `entities_in_obb_on_layers` with `SpatialLayers::ALL`.
+    fn entities_in_obb(&self, center: Vec3, half_extents: Vec3, rotation: Quat) -> Vec<Entity> {
+        self.entities_in_obb_on_layers(center, half_extents, rotation, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_in_obb`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation tests the entities within the bounding sphere of the box.
+    fn entities_in_obb_on_layers(
+        &self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let shape = ObbQuery {
+            center,
+            half_extents,
+            rotation,
+        };
+        entities_in_bounded_shape(self, center, half_extents.length(), mask, &shape)
+    }
+
+    /// Returns a list of all entities within `radius` of the line through `center` along `axis`,
+    /// ignoring the distance along the axis itself.
+    ///
+    /// If `height_range` is given, only entities whose coordinate along `axis` (i.e.
+    /// `position.dot(axis)`, the world-space Y coordinate for `Dir3::Y`) is within the inclusive
+    /// range are returned. A negative or NaN `radius` returns nothing. This method *MUST* return
+    /// all entities inside the cylinder, and it *MUST* not return any entities outside of it.
+    ///
+    /// The default implementation calls `entities_in_cylinder_on_layers` with `SpatialLayers::ALL`.
+    fn entities_in_cylinder(
+        &self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+    ) -> Vec<Entity> {
+        self.entities_in_cylinder_on_layers(center, axis, radius, height_range, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_in_cylinder`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation tests every entity.
+    fn entities_in_cylinder_on_layers(
+        &self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let shape = CylinderQuery {
+            center,
+            axis: *axis,
+            radius,
+            height_range,
+        };
+        if shape.is_empty() {
+            return Vec::new();
+        }
+
+        entities_in_bounded_shape(self, center, f32::INFINITY, mask, &shape)
+    }
+
+    /// Returns a list of all entities at least `min_radius` and at most `max_radius` away from
+    /// the center.
+    ///
+    /// Both radii are inclusive. This method *MUST* return all entities inside the shell, and it
+    /// *MUST* not return any entities outside of it.
+    ///
+    /// The default implementation calls `entities_in_shell_on_layers` with `SpatialLayers::ALL`.
+    fn entities_in_shell(&self, center: Vec3, min_radius: f32, max_radius: f32) -> Vec<Entity> {
+        self.entities_in_shell_on_layers(center, min_radius, max_radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_in_shell`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation tests the entities within `max_radius` of the center.
+    fn entities_in_shell_on_layers(
+        &self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let shape = ShellQuery {
+            center,
+            min_radius,
+            max_radius,
+        };
+        entities_in_bounded_shape(self, center, max_radius, mask, &shape)
+    }
+
+    /// Returns a list of all entities whose bounding sphere intersects the given sphere.
+    ///
+    /// This method *MUST* return all entities whose extent overlaps the sphere, and it *MUST* not
+    /// return any entities whose extent doesn't. The default implementation calls
+    /// `entities_overlapping_sphere_on_layers` with `SpatialLayers::ALL`.
+    fn entities_overlapping_sphere(&self, center: Vec3, radius: f32) -> Vec<Entity> {
+        self.entities_overlapping_sphere_on_layers(center, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_overlapping_sphere`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation treats every entity as a point.
+    fn entities_overlapping_sphere_on_layers(
+        &self,
+        center: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+        self.visit_in_radius_on_layers(center, radius, mask, &mut |entity, _| {
+            found_entities.push(entity)
+        });
+
+        found_entities
+    }
+
+    /// Returns a list of all entities whose bounding sphere intersects the axis-aligned box spanned
+    /// by `min` and `max`.
+    ///
+    /// This method *MUST* return all entities whose extent overlaps the box, and it *MUST* not
+    /// return any entities whose extent doesn't. The default implementation calls
+    /// `entities_overlapping_aabb_on_layers` with `SpatialLayers::ALL`.
+    fn entities_overlapping_aabb(&self, min: Vec3, max: Vec3) -> Vec<Entity> {
+        self.entities_overlapping_aabb_on_layers(min, max, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_overlapping_aabb`, but only returns entities whose layers match `mask`.
+    ///
+    /// The default implementation treats every entity as a point.
+    fn entities_overlapping_aabb_on_layers(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        self.entities_in_aabb_on_layers(min, max, mask)
+    }
+
+    /// Calls `visit` with every unordered pair of entities which are within `distance` of each
+    /// other.
+    ///
+    /// This method *MUST* visit each such pair exactly once, in either order, and it *MUST* not
+    /// visit any pairs further apart, or pair an entity with itself.
+    ///
+    /// The default implementation calls `visit_pairs_within_on_layers` with `SpatialLayers::ALL`.
+    fn visit_pairs_within(&self, distance: f32, visit: &mut dyn FnMut(Entity, Entity)) {
+        self.visit_pairs_within_on_layers(distance, SpatialLayers::ALL, visit);
+    }
+
+    /// Same as `visit_pairs_within`, but only pairs up entities whose layers match `mask`.
+    ///
+    /// The default implementation runs a radius lookup around every entity.
+    fn visit_pairs_within_on_layers(
+        &self,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        if distance.is_nan() || distance < 0.0 {
+            return;
+        }
+
+        self.visit_in_radius_on_layers(Vec3::ZERO, f32::INFINITY, mask, &mut |entity, position| {
+            self.visit_in_radius_on_layers(position, distance, mask, &mut |other, _| {
+                // every pair is found from both sides, only visit it from the smaller entity
+                if entity < other {
+                    visit(entity, other);
+                }
+            });
+        });
+    }
+
+    /// Calls `visit` with every pair of an entity of this algorithm and an entity of `other` which
+    /// are within `distance` of each other, the entity of this algorithm first.
+    ///
+    /// This method *MUST* visit each such pair exactly once, and it *MUST* not visit any pairs
+    /// further apart. An entity indexed by both algorithms is paired with itself. The default
+    /// implementation calls `visit_pairs_between_on_layers` with `SpatialLayers::ALL`.
+    fn visit_pairs_between(
+        &self,
+        other: &dyn SpatialLookupAlgorithm,
+        distance: f32,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        self.visit_pairs_between_on_layers(other, distance, SpatialLayers::ALL, visit);
+    }
+
+    /// Same as `visit_pairs_between`, but only pairs up entities of either algorithm whose layers
+    /// match `mask`. Only called if both algorithms support layers.
+    ///
+    /// The default implementation runs a radius lookup in `other` around every entity of this
+    /// algorithm, so algorithms should override it to walk themselves against an `other` of the
+    /// same type.
+    fn visit_pairs_between_on_layers(
+        &self,
+        other: &dyn SpatialLookupAlgorithm,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        visit_pairs_between_by_lookup(self, other, distance, mask, visit);
+    }
+
+    /// Whether the algorithm indexes the layers it is given in `prepare_with_layers` and
+    /// `set_entity_layers`, and implements the `_on_layers` lookups by skipping entities on other
+    /// layers while walking the index. If this returns false, the `SpatialLookupState` will fall
+    /// back to filtering the results of the regular lookups.
+    fn supports_layers(&self) -> bool {
+        false
+    }
+
+    /// Whether the algorithm reports the positions it indexed the entities at, by overriding
+    /// `visit_in_radius` or `entities_in_radius_with_distance`. If this returns false, the
+    /// `SpatialLookupState` only calls `entities_in_radius` for lookups, and builds all other
+    /// lookups on top of it with the positions it tracks itself.
+    fn reports_positions(&self) -> bool {
+        false
+    }
+
+    /// Returns `Some` if every lookup of the algorithm returns each entity at most once, so that
+    /// `SpatialQuery` can skip guarding against duplicates, see `UniqueEntities`.
+    ///
+    /// The default returns `None`, which makes `SpatialQuery` skip repeated entities instead.
+    fn returns_unique_entities(&self) -> Option<UniqueEntities> {
+        None
+    }
+
+    /// Returns the algorithm as `Any`, so that algorithms can recognize an `other` algorithm of
+    /// their own type, e.g. in `visit_pairs_between_on_layers`.
+    ///
+    /// The default returns `None`, so the algorithm is never downcast.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
+
     /// Whether the algorithm supports incremental updates via `insert_entity` / `remove_entity` /
     /// `update_entity`. If this returns false, the `SpatialLookupState` will fall back to
     /// requesting a full rebuild.
@@ -109,57 +894,861 @@ pub trait SpatialLookupAlgorithm {
     /// Update a single entity's position (incremental update path).
     fn update_entity(&mut self, _entity: Entity, _position: Vec3) {}
 
+    /// Insert a single entity with a bounding sphere radius (incremental update path).
+    ///
+    /// The default implementation ignores the extent.
+    fn insert_entity_with_extent(&mut self, entity: Entity, position: Vec3, _extent: f32) {
+        self.insert_entity(entity, position);
+    }
+
+    /// Update a single entity's position and bounding sphere radius (incremental update path).
+    ///
+    /// The default implementation ignores the extent.
+    fn update_entity_with_extent(&mut self, entity: Entity, position: Vec3, _extent: f32) {
+        self.update_entity(entity, position);
+    }
+
+    /// Update a single entity's layers (incremental update path). Entities inserted through the
+    /// incremental path are on `SpatialLayers::DEFAULT` until this is called.
+    fn set_entity_layers(&mut self, _entity: Entity, _layers: SpatialLayers) {}
+
     /// Draw debug gizmos.
     fn debug_gizmos(&self, _gizmos: &mut Gizmos) {}
 }
 
-/// Resource which holds the configured `SpatialLookupAlgorithm` and relevant state.
+/// Returns the entities within `radius` of `center` whose layers match `mask` and which are
+/// inside `shape`.
+///
+/// Used by the default implementations of the shape lookups, with a sphere bounding the shape.
+fn entities_in_bounded_shape<A: SpatialLookupAlgorithm + ?Sized>(
+    algorithm: &A,
+    center: Vec3,
+    radius: f32,
+    mask: SpatialLayers,
+    shape: &impl QueryShape,
+) -> Vec<Entity> {
+    let mut found_entities = Vec::new();
+    algorithm.visit_in_radius_on_layers(
+        center,
+        bounding_radius(radius),
+        mask,
+        &mut |entity, position| {
+            if shape.contains_point(position) {
+                found_entities.push(entity);
+            }
+        },
+    );
+
+    found_entities
+}
+
+/// Calls `visit` with every pair of an entity of `algorithm` and an entity of `other` within
+/// `distance` of each other and on `mask`, by running a radius lookup in `other` around every
+/// entity.
+///
+/// Used by `visit_pairs_between_on_layers` when the two algorithms can't be walked against each
+/// other.
+pub(crate) fn visit_pairs_between_by_lookup<A: SpatialLookupAlgorithm + ?Sized>(
+    algorithm: &A,
+    other: &dyn SpatialLookupAlgorithm,
+    distance: f32,
+    mask: SpatialLayers,
+    visit: &mut dyn FnMut(Entity, Entity),
+) {
+    if distance.is_nan() || distance < 0.0 {
+        return;
+    }
+
+    algorithm.visit_in_radius_on_layers(
+        Vec3::ZERO,
+        f32::INFINITY,
+        mask,
+        &mut |entity, position| {
+            other.visit_in_radius_on_layers(position, distance, mask, &mut |other_entity, _| {
+                visit(entity, other_entity);
+            });
+        },
+    );
+}
+
+/// Grows the radius of a bounding sphere slightly, so rounding never drops points on the surface
+/// of the bounded shape.
+fn bounding_radius(radius: f32) -> f32 {
+    radius + radius.abs() * 1e-5
+}
+
+/// Lookups of an algorithm which doesn't report positions, see
+/// `SpatialLookupAlgorithm::reports_positions`, with the positions tracked by the
+/// `SpatialLookupState` filled in.
+///
+/// Only overrides `visit_in_radius`, so every other lookup uses its default implementation.
+struct TrackedPositions<'a> {
+    algorithm: &'a (dyn SpatialLookupAlgorithm + Send + Sync),
+    entities: &'a [(Entity, Vec3)],
+    indices: &'a HashMap<Entity, usize>,
+}
+
+impl SpatialLookupAlgorithm for TrackedPositions<'_> {
+    fn prepare(&mut self, _entities: &[(Entity, Vec3)]) {
+        unreachable!("TrackedPositions is only used for lookups");
+    }
+
+    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        self.algorithm.entities_in_radius(sample_point, radius)
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        for entity in self.algorithm.entities_in_radius(sample_point, radius) {
+            if let Some(&idx) = self.indices.get(&entity) {
+                visit(entity, self.entities[idx].1);
+            }
+        }
+    }
+
+    fn returns_unique_entities(&self) -> Option<UniqueEntities> {
+        // every other lookup is built on the `entities_in_radius` of the algorithm
+        self.algorithm.returns_unique_entities()
+    }
+}
+
+/// The algorithm of a `SpatialLookupState` to run lookups on.
+enum Lookup<'a> {
+    Algorithm(&'a (dyn SpatialLookupAlgorithm + Send + Sync)),
+    TrackedPositions(TrackedPositions<'a>),
+}
+
+impl<'a> Deref for Lookup<'a> {
+    type Target = dyn SpatialLookupAlgorithm + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Lookup::Algorithm(algorithm) => *algorithm,
+            Lookup::TrackedPositions(tracked) => tracked,
+        }
+    }
+}
+
+/// Resource which holds the configured `SpatialLookupAlgorithm` and relevant state of index `I`.
 #[derive(Resource)]
-pub struct SpatialLookupState {
+pub struct SpatialLookupState<I: SpatialIndex = DefaultSpatialIndex> {
     /// Dense list of tracked entities + positions.
     pub entities: Vec<(Entity, Vec3)>,
+    /// Bounding sphere radius of each entity in `entities`, 0 for points.
+    extents: Vec<f32>,
+    /// Layers of each entity in `entities`.
+    layers: Vec<SpatialLayers>,
     /// Entity -> index in `entities` for O(1) updates/removals.
     indices: HashMap<Entity, usize>,
     pub algorithm: Box<dyn SpatialLookupAlgorithm + Send + Sync>,
     initialized: bool,
     full_rebuild_requested: bool,
+    /// Entities whose entry changed since each sub-index last synced with the index, keyed by the
+    /// type of the sub-index, see `SpatialFilterPlugin`.
+    changes: HashMap<TypeId, HashSet<Entity>>,
+    index: PhantomData<I>,
 }
 
-impl Default for SpatialLookupState {
+impl<I: SpatialIndex> Default for SpatialLookupState<I> {
     fn default() -> Self {
         SpatialLookupState {
             entities: Vec::new(),
+            extents: Vec::new(),
+            layers: Vec::new(),
             indices: HashMap::default(),
             algorithm: Box::new(algorithms::Naive::default()),
             initialized: false,
             full_rebuild_requested: true, // first prepare builds everything
+            changes: HashMap::default(),
+            index: PhantomData,
         }
     }
 }
 
 impl SpatialLookupState {
+    /// Creates the state of the default index, using the given algorithm.
+    ///
+    /// Use `SpatialLookupState::<I>::new` for other indices.
     pub fn with_algorithm<T: SpatialLookupAlgorithm + Send + Sync + 'static>(algorithm: T) -> Self {
+        Self::new(algorithm)
+    }
+}
+
+impl<I: SpatialIndex> SpatialLookupState<I> {
+    /// Creates the state of index `I`, using the given algorithm.
+    pub fn new<T: SpatialLookupAlgorithm + Send + Sync + 'static>(algorithm: T) -> Self {
         Self {
             entities: vec![],
+            extents: vec![],
+            layers: vec![],
             indices: HashMap::default(),
             algorithm: Box::new(algorithm),
             initialized: false,
             full_rebuild_requested: true,
+            changes: HashMap::default(),
+            index: PhantomData,
+        }
+    }
+
+    /// Whether the algorithm in use returns each entity at most once, see
+    /// `SpatialLookupAlgorithm::returns_unique_entities`.
+    ///
+    /// Checked against the algorithm in use rather than the one given to `new`, because
+    /// `algorithm` may be replaced at any time.
+    pub(crate) fn returns_unique_entities(&self) -> Option<UniqueEntities> {
+        self.algorithm.returns_unique_entities()
+    }
+
+    /// Returns the indexed position of the entity, or `None` if it isn't tracked.
+    pub fn position_of(&self, entity: Entity) -> Option<Vec3> {
+        self.indices.get(&entity).map(|&idx| self.entities[idx].1)
+    }
+
+    /// Returns the indexed bounding sphere radius of the entity, or `None` if it isn't tracked.
+    pub fn extent_of(&self, entity: Entity) -> Option<f32> {
+        let idx = *self.indices.get(&entity)?;
+        Some(self.extents.get(idx).copied().unwrap_or_default())
+    }
+
+    /// Returns the indexed layers of the entity, or `None` if it isn't tracked.
+    pub fn layers_of(&self, entity: Entity) -> Option<SpatialLayers> {
+        let idx = *self.indices.get(&entity)?;
+        Some(self.layers.get(idx).copied().unwrap_or_default())
+    }
+
+    /// Sets the layers of a tracked entity, and (if supported) in the algorithm. Does nothing if
+    /// the entity isn't tracked.
+    pub fn set_layers(&mut self, entity: Entity, layers: SpatialLayers) {
+        let Some(&idx) = self.indices.get(&entity) else {
+            return;
+        };
+        self.layers
+            .resize(self.entities.len(), SpatialLayers::DEFAULT);
+        if self.layers[idx] == layers {
+            return;
+        }
+        self.layers[idx] = layers;
+        self.mark_changed(entity);
+
+        // algorithms which don't index layers have their results filtered by the state instead
+        if !self.algorithm.supports_layers() {
+            return;
+        }
+        if self.initialized && self.algorithm.supports_incremental() {
+            self.algorithm.set_entity_layers(entity, layers);
+        } else {
+            self.full_rebuild_requested = true;
+        }
+    }
+
+    /// Removes every entity whose layers don't match `mask`, see `SpatialLayers::matches`.
+    pub fn retain_on_layers(&self, entities: &mut Vec<Entity>, mask: SpatialLayers) {
+        if mask == SpatialLayers::ALL {
+            return;
         }
+
+        entities.retain(|&entity| self.is_on_layers(entity, mask));
+    }
+
+    /// Whether the entity is tracked and its layers match `mask`.
+    ///
+    /// Only used to filter the results of algorithms which don't support layers.
+    fn is_on_layers(&self, entity: Entity, mask: SpatialLayers) -> bool {
+        self.layers_of(entity)
+            .is_some_and(|layers| layers.matches(mask))
+    }
+
+    /// Removes the results of a lookup by an algorithm which doesn't support layers whose layers
+    /// don't match `mask`.
+    fn filtered_on_layers(&self, mut entities: Vec<Entity>, mask: SpatialLayers) -> Vec<Entity> {
+        self.retain_on_layers(&mut entities, mask);
+
+        entities
+    }
+
+    /// The algorithm to run lookups on, with the tracked positions filled in if it doesn't report
+    /// them itself.
+    fn lookup(&self) -> Lookup<'_> {
+        if self.algorithm.reports_positions() {
+            return Lookup::Algorithm(&*self.algorithm);
+        }
+
+        Lookup::TrackedPositions(TrackedPositions {
+            algorithm: &*self.algorithm,
+            entities: &self.entities,
+            indices: &self.indices,
+        })
+    }
+
+    /// Returns a list of entities whose bounding sphere intersects the given sphere.
+    pub fn entities_overlapping_sphere(&self, center: Vec3, radius: f32) -> Vec<Entity> {
+        self.lookup().entities_overlapping_sphere(center, radius)
+    }
+
+    /// Same as `entities_overlapping_sphere`, but only returns entities whose layers match `mask`.
+    pub fn entities_overlapping_sphere_on_layers(
+        &self,
+        center: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .entities_overlapping_sphere_on_layers(center, radius, mask);
+        }
+
+        self.filtered_on_layers(self.entities_overlapping_sphere(center, radius), mask)
+    }
+
+    /// Returns a list of entities whose bounding sphere intersects the axis-aligned box spanned by
+    /// `min` and `max`.
+    pub fn entities_overlapping_aabb(&self, min: Vec3, max: Vec3) -> Vec<Entity> {
+        self.lookup().entities_overlapping_aabb(min, max)
+    }
+
+    /// Same as `entities_overlapping_aabb`, but only returns entities whose layers match `mask`.
+    pub fn entities_overlapping_aabb_on_layers(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .entities_overlapping_aabb_on_layers(min, max, mask);
+        }
+
+        self.filtered_on_layers(self.entities_overlapping_aabb(min, max), mask)
     }
 
     /// Returns a list of entities in the radius of the sample point.
     pub fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
-        self.algorithm.entities_in_radius(sample_point, radius)
+        self.lookup().entities_in_radius(sample_point, radius)
+    }
+
+    /// Calls `visit` with every entity in the radius of the sample point and its indexed position,
+    /// without allocating.
+    pub fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        self.lookup().visit_in_radius(sample_point, radius, visit);
+    }
+
+    /// Same as `visit_in_radius`, but skips entities whose layers don't match `mask` during the
+    /// traversal.
+    pub fn visit_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Vec3),
+    ) {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .visit_in_radius_on_layers(sample_point, radius, mask, visit);
+        }
+        if mask == SpatialLayers::ALL {
+            return self.visit_in_radius(sample_point, radius, visit);
+        }
+
+        self.lookup()
+            .visit_in_radius(sample_point, radius, &mut |entity, position| {
+                if self.is_on_layers(entity, mask) {
+                    visit(entity, position);
+                }
+            });
+    }
+
+    /// Returns a list of entities in the radius of the sample point, together with their indexed
+    /// positions and squared distances to the sample point.
+    pub fn entities_in_radius_with_distance(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> Vec<(Entity, Vec3, f32)> {
+        self.lookup()
+            .entities_in_radius_with_distance(sample_point, radius)
+    }
+
+    /// Same as `entities_in_radius_with_distance`, but only returns entities whose layers match
+    /// `mask`.
+    pub fn entities_in_radius_with_distance_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, Vec3, f32)> {
+        if mask == SpatialLayers::ALL {
+            return self.entities_in_radius_with_distance(sample_point, radius);
+        }
+
+        let mut found = Vec::new();
+        self.visit_in_radius_on_layers(sample_point, radius, mask, &mut |entity, position| {
+            found.push((entity, position, position.distance_squared(sample_point)));
+        });
+
+        found
+    }
+
+    /// Runs a radius lookup for each `(sample_point, radius)` pair, in parallel on the
+    /// `ComputeTaskPool`.
+    ///
+    /// The results are returned in the order of `queries`, without allocating per lookup.
+    pub fn batch_in_radius(&self, queries: &[(Vec3, f32)]) -> batch::BatchResults {
+        batch::batch_in_radius(queries, |sample_point, radius, visit| {
+            self.lookup().visit_in_radius(sample_point, radius, visit)
+        })
+    }
+
+    /// Same as `batch_in_radius`, but only returns entities whose layers match `mask`.
+    pub fn batch_in_radius_on_layers(
+        &self,
+        queries: &[(Vec3, f32)],
+        mask: SpatialLayers,
+    ) -> batch::BatchResults {
+        batch::batch_in_radius(queries, |sample_point, radius, visit| {
+            self.visit_in_radius_on_layers(sample_point, radius, mask, visit)
+        })
+    }
+
+    /// Calls `visit` with every unordered pair of entities within `distance` of each other,
+    /// exactly once per pair.
+    pub fn visit_pairs_within(&self, distance: f32, visit: &mut dyn FnMut(Entity, Entity)) {
+        self.lookup().visit_pairs_within(distance, visit);
+    }
+
+    /// Same as `visit_pairs_within`, but only pairs up entities whose layers match `mask`.
+    pub fn visit_pairs_within_on_layers(
+        &self,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .visit_pairs_within_on_layers(distance, mask, visit);
+        }
+        if mask == SpatialLayers::ALL {
+            return self.visit_pairs_within(distance, visit);
+        }
+
+        self.lookup().visit_pairs_within(distance, &mut |a, b| {
+            if self.is_on_layers(a, mask) && self.is_on_layers(b, mask) {
+                visit(a, b);
+            }
+        });
+    }
+
+    /// Calls `visit` with every pair of an entity of this index and an entity of `other` within
+    /// `distance` of each other, the entity of this index first, exactly once per pair.
+    ///
+    /// `other` may be a different index or a sub-index. An entity tracked by both is paired with
+    /// itself.
+    pub fn visit_pairs_between<I2: SpatialIndex>(
+        &self,
+        other: &SpatialLookupState<I2>,
+        distance: f32,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        self.lookup()
+            .visit_pairs_between(&*other.lookup(), distance, visit);
+    }
+
+    /// Same as `visit_pairs_between`, but only pairs up entities of either index whose layers
+    /// match `mask`.
+    pub fn visit_pairs_between_on_layers<I2: SpatialIndex>(
+        &self,
+        other: &SpatialLookupState<I2>,
+        distance: f32,
+        mask: SpatialLayers,
+        visit: &mut dyn FnMut(Entity, Entity),
+    ) {
+        if self.lookup().supports_layers() && other.lookup().supports_layers() {
+            return self.lookup().visit_pairs_between_on_layers(
+                &*other.lookup(),
+                distance,
+                mask,
+                visit,
+            );
+        }
+        if mask == SpatialLayers::ALL {
+            return self.visit_pairs_between(other, distance, visit);
+        }
+
+        self.lookup()
+            .visit_pairs_between(&*other.lookup(), distance, &mut |a, b| {
+                if self.is_on_layers(a, mask) && other.is_on_layers(b, mask) {
+                    visit(a, b);
+                }
+            });
+    }
+
+    /// Returns the number of entities in the radius of the sample point.
+    pub fn count_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.lookup().count_in_radius(sample_point, radius)
+    }
+
+    /// Same as `count_in_radius`, but only counts entities whose layers match `mask`.
+    pub fn count_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> usize {
+        if mask == SpatialLayers::ALL {
+            return self.count_in_radius(sample_point, radius);
+        }
+
+        let mut count = 0;
+        self.visit_in_radius_on_layers(sample_point, radius, mask, &mut |_, _| count += 1);
+
+        count
+    }
+
+    /// Returns an approximate number of entities in the radius of the sample point.
+    pub fn estimate_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.lookup().estimate_in_radius(sample_point, radius)
+    }
+
+    /// Returns a list of entities in the radius of the sample point, sorted nearest first.
+    ///
+    /// Entities at the same distance are ordered by `Entity`.
+    pub fn entities_in_radius_sorted(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        self.entities_in_radius_sorted_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `entities_in_radius_sorted`, but only returns entities whose layers match `mask`.
+    pub fn entities_in_radius_sorted_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        let mut found = self.entities_in_radius_with_distance_on_layers(sample_point, radius, mask);
+        found.sort_unstable_by_key(|(entity, _, distance_squared)| {
+            (FloatOrd(*distance_squared), *entity)
+        });
+
+        found.into_iter().map(|(entity, _, _)| entity).collect()
+    }
+
+    /// Returns a list of entities inside the axis-aligned box spanned by `min` and `max`.
+    pub fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Vec<Entity> {
+        self.lookup().entities_in_aabb(min, max)
+    }
+
+    /// Same as `entities_in_aabb`, but only returns entities whose layers match `mask`.
+    pub fn entities_in_aabb_on_layers(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self.lookup().entities_in_aabb_on_layers(min, max, mask);
+        }
+
+        self.filtered_on_layers(self.entities_in_aabb(min, max), mask)
+    }
+
+    /// Returns up to `k` entities closest to the sample point and their distances, nearest first.
+    pub fn nearest_k(
+        &self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Option<Entity>,
+    ) -> Vec<(Entity, f32)> {
+        self.lookup()
+            .nearest_k(sample_point, k, max_distance, exclude)
+    }
+
+    /// Same as `nearest_k`, but only considers entities whose layers match `mask`, so `k` entities
+    /// are returned whenever there are that many on the mask within `max_distance`.
+    pub fn nearest_k_on_layers(
+        &self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Option<Entity>,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .nearest_k_on_layers(sample_point, k, max_distance, exclude, mask);
+        }
+        if mask == SpatialLayers::ALL {
+            return self.nearest_k(sample_point, k, max_distance, exclude);
+        }
+
+        // walk outwards until `k` entities on the mask are found
+        self.lookup()
+            .nearest_iter(sample_point)
+            .filter(|&(entity, _)| Some(entity) != exclude && self.is_on_layers(entity, mask))
+            .take_while(|&(_, distance)| distance <= max_distance)
+            .take(k)
+            .collect()
+    }
+
+    /// Returns a lazy iterator over all entities and their distances, nearest first.
+    pub fn nearest_iter(&self, sample_point: Vec3) -> Box<dyn Iterator<Item = (Entity, f32)> + '_> {
+        match self.lookup() {
+            Lookup::Algorithm(algorithm) => algorithm.nearest_iter(sample_point),
+            // the default implementation collects every entity up front anyway
+            tracked => Box::new(
+                tracked
+                    .nearest_iter(sample_point)
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+        }
+    }
+
+    /// Same as `nearest_iter`, but only yields entities whose layers match `mask`.
+    pub fn nearest_iter_on_layers(
+        &self,
+        sample_point: Vec3,
+        mask: SpatialLayers,
+    ) -> Box<dyn Iterator<Item = (Entity, f32)> + '_> {
+        if let Lookup::Algorithm(algorithm) = self.lookup()
+            && algorithm.supports_layers()
+        {
+            return algorithm.nearest_iter_on_layers(sample_point, mask);
+        }
+        if mask == SpatialLayers::ALL {
+            return self.nearest_iter(sample_point);
+        }
+
+        Box::new(
+            self.nearest_iter(sample_point)
+                .filter(move |&(entity, _)| self.is_on_layers(entity, mask)),
+        )
+    }
+
+    /// Returns entities within `thickness` of a ray or segment, and their distance along it,
+    /// sorted by that distance.
+    pub fn entities_along_ray(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+    ) -> Vec<(Entity, f32)> {
+        self.lookup()
+            .entities_along_ray(origin, direction, max_t, thickness)
+    }
+
+    /// Same as `entities_along_ray`, but only returns entities whose layers match `mask`.
+    pub fn entities_along_ray_on_layers(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+        mask: SpatialLayers,
+    ) -> Vec<(Entity, f32)> {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .entities_along_ray_on_layers(origin, direction, max_t, thickness, mask);
+        }
+
+        let mut found = self.entities_along_ray(origin, direction, max_t, thickness);
+        if mask != SpatialLayers::ALL {
+            found.retain(|&(entity, _)| self.is_on_layers(entity, mask));
+        }
+
+        found
+    }
+
+    /// Returns a list of entities inside the frustum.
+    pub fn entities_in_frustum(&self, frustum: &Frustum) -> Vec<Entity> {
+        self.lookup().entities_in_frustum(frustum)
+    }
+
+    /// Same as `entities_in_frustum`, but only returns entities whose layers match `mask`.
+    pub fn entities_in_frustum_on_layers(
+        &self,
+        frustum: &Frustum,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self.lookup().entities_in_frustum_on_layers(frustum, mask);
+        }
+
+        self.filtered_on_layers(self.entities_in_frustum(frustum), mask)
+    }
+
+    /// Returns a list of entities inside the cone at `apex` opening towards `direction`.
+    pub fn entities_in_cone(
+        &self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+    ) -> Vec<Entity> {
+        self.lookup()
+            .entities_in_cone(apex, direction, half_angle, range)
+    }
+
+    /// Same as `entities_in_cone`, but only returns entities whose layers match `mask`.
+    pub fn entities_in_cone_on_layers(
+        &self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .entities_in_cone_on_layers(apex, direction, half_angle, range, mask);
+        }
+
+        let found = self.entities_in_cone(apex, direction, half_angle, range);
+        self.filtered_on_layers(found, mask)
+    }
+
+    /// Returns a list of entities within `radius` of the segment from `a` to `b`.
+    pub fn entities_in_capsule(&self, a: Vec3, b: Vec3, radius: f32) -> Vec<Entity> {
+        self.lookup().entities_in_capsule(a, b, radius)
+    }
+
+    /// Same as `entities_in_capsule`, but only returns entities whose layers match `mask`.
+    pub fn entities_in_capsule_on_layers(
+        &self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .entities_in_capsule_on_layers(a, b, radius, mask);
+        }
+
+        self.filtered_on_layers(self.entities_in_capsule(a, b, radius), mask)
+    }
+
+    /// Returns a list of entities inside the oriented box.
+    pub fn entities_in_obb(&self, center: Vec3, half_extents: Vec3, rotation: Quat) -> Vec<Entity> {
+        self.lookup()
+            .entities_in_obb(center, half_extents, rotation)
+    }
+
+    /// Same as `entities_in_obb`, but only returns entities whose layers match `mask`.
+    pub fn entities_in_obb_on_layers(
+        &self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .entities_in_obb_on_layers(center, half_extents, rotation, mask);
+        }
+
+        let found = self.entities_in_obb(center, half_extents, rotation);
+        self.filtered_on_layers(found, mask)
+    }
+
+    /// Returns a list of entities within `radius` of the line through `center` along `axis`.
+    pub fn entities_in_cylinder(
+        &self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+    ) -> Vec<Entity> {
+        self.lookup()
+            .entities_in_cylinder(center, axis, radius, height_range)
+    }
+
+    /// Same as `entities_in_cylinder`, but only returns entities whose layers match `mask`.
+    pub fn entities_in_cylinder_on_layers(
+        &self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self.lookup().entities_in_cylinder_on_layers(
+                center,
+                axis,
+                radius,
+                height_range,
+                mask,
+            );
+        }
+
+        let found = self.entities_in_cylinder(center, axis, radius, height_range);
+        self.filtered_on_layers(found, mask)
+    }
+
+    /// Returns a list of entities between `min_radius` and `max_radius` away from the center.
+    pub fn entities_in_shell(&self, center: Vec3, min_radius: f32, max_radius: f32) -> Vec<Entity> {
+        self.lookup()
+            .entities_in_shell(center, min_radius, max_radius)
+    }
+
+    /// Same as `entities_in_shell`, but only returns entities whose layers match `mask`.
+    pub fn entities_in_shell_on_layers(
+        &self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+        mask: SpatialLayers,
+    ) -> Vec<Entity> {
+        if self.lookup().supports_layers() {
+            return self
+                .lookup()
+                .entities_in_shell_on_layers(center, min_radius, max_radius, mask);
+        }
+
+        let found = self.entities_in_shell(center, min_radius, max_radius);
+        self.filtered_on_layers(found, mask)
     }
 
     /// Inserts or updates an entity in the tracked set, and (if supported) in the algorithm.
     pub fn upsert_entity(&mut self, entity: Entity, position: Vec3) {
+        let extent = self.extent_of(entity).unwrap_or_default();
+        self.upsert_entity_with_extent(entity, position, extent);
+    }
+
+    /// Same as `upsert_entity`, but also sets the entity's bounding sphere radius.
+    pub fn upsert_entity_with_extent(&mut self, entity: Entity, position: Vec3, extent: f32) {
+        // `entities` is public, so extents may have to catch up with entities added directly
+        self.extents.resize(self.entities.len(), 0.0);
+        self.layers
+            .resize(self.entities.len(), SpatialLayers::DEFAULT);
+
+        self.mark_changed(entity);
+
         if let Some(&idx) = self.indices.get(&entity) {
             self.entities[idx].1 = position;
+            self.extents[idx] = extent;
 
             if self.initialized && self.algorithm.supports_incremental() {
-                self.algorithm.update_entity(entity, position);
+                self.algorithm
+                    .update_entity_with_extent(entity, position, extent);
             } else {
                 self.full_rebuild_requested = true;
             }
@@ -168,10 +1757,13 @@ impl SpatialLookupState {
 
         let idx = self.entities.len();
         self.entities.push((entity, position));
+        self.extents.push(extent);
+        self.layers.push(SpatialLayers::DEFAULT);
         self.indices.insert(entity, idx);
 
         if self.initialized && self.algorithm.supports_incremental() {
-            self.algorithm.insert_entity(entity, position);
+            self.algorithm
+                .insert_entity_with_extent(entity, position, extent);
         } else {
             self.full_rebuild_requested = true;
         }
@@ -179,12 +1771,20 @@ impl SpatialLookupState {
 
     /// Removes an entity from the tracked set, and (if supported) from the algorithm.
     pub fn remove_entity(&mut self, entity: Entity) {
-        let Some(idx) = self.indices.remove(&entity) else { return; };
+        let Some(idx) = self.indices.remove(&entity) else {
+            return;
+        };
+        self.mark_changed(entity);
+        self.extents.resize(self.entities.len(), 0.0);
+        self.layers
+            .resize(self.entities.len(), SpatialLayers::DEFAULT);
 
         // swap_remove for O(1)
         let last = self.entities.len() - 1;
         self.entities.swap(idx, last);
         let _removed = self.entities.pop();
+        self.extents.swap_remove(idx);
+        self.layers.swap_remove(idx);
 
         if idx != last {
             let swapped_entity = self.entities[idx].0;
@@ -204,7 +1804,20 @@ impl SpatialLookupState {
     /// - Runs again only when a full rebuild is requested.
     pub fn prepare_algorithm(&mut self) {
         if !self.initialized || self.full_rebuild_requested {
-            self.algorithm.prepare(&self.entities);
+            // `entities` is public, so the indices may have to catch up with entities set directly
+            if self.indices.len() != self.entities.len() {
+                self.indices = self
+                    .entities
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, &(entity, _))| (entity, idx))
+                    .collect();
+            }
+            self.extents.resize(self.entities.len(), 0.0);
+            self.layers
+                .resize(self.entities.len(), SpatialLayers::DEFAULT);
+            self.algorithm
+                .prepare_with_layers(&self.entities, &self.extents, &self.layers);
             self.initialized = true;
             self.full_rebuild_requested = false;
         }
@@ -214,17 +1827,62 @@ impl SpatialLookupState {
     pub fn request_full_rebuild(&mut self) {
         self.full_rebuild_requested = true;
     }
+
+    /// Records that the entry of `entity` changed, for the sub-indices to sync with.
+    fn mark_changed(&mut self, entity: Entity) {
+        for changes in self.changes.values_mut() {
+            changes.insert(entity);
+        }
+    }
+
+    /// Moves the entities whose entry changed since the last call into `changed`, for the
+    /// sub-index `sub_index` to sync with.
+    ///
+    /// Returns false if changes weren't recorded for the sub-index until now, so it has to sync
+    /// with every entity once.
+    pub(crate) fn take_changes(
+        &mut self,
+        sub_index: TypeId,
+        changed: &mut HashSet<Entity>,
+    ) -> bool {
+        match self.changes.entry(sub_index) {
+            Entry::Occupied(mut changes) => {
+                changed.extend(changes.get_mut().drain());
+                true
+            }
+            Entry::Vacant(changes) => {
+                changes.insert(HashSet::default());
+                false
+            }
+        }
+    }
 }
 
-impl Plugin for SpatialQueriesPlugin {
+impl<I: SpatialIndex, P: SpatialPosition> Plugin for SpatialQueriesPlugin<I, P> {
     fn build(&self, app: &mut App) {
-        app.insert_resource(SpatialLookupState::default())
+        // Keep a `SpatialLookupState<I>` inserted before the plugin, so each index can be given its
+        // own algorithm.
+        app.init_resource::<SpatialLookupState<I>>()
             // Initial prepare / fallback rebuild
-            .add_systems(First, prepare_spatial_lookup.in_set(PrepareSpatialLookup))
+            .add_systems(
+                First,
+                prepare_spatial_lookup::<I, P>
+                    .in_set(PrepareSpatialLookup)
+                    .before(filtered::SyncFilteredLookup),
+            )
             // Incremental lifecycle hooks
-            .add_observer(spatial_entity_added)
-            .add_observer(spatial_entity_removed)
-            .add_systems(FixedLast,spatial_transform_changed);
+            .add_observer(spatial_entity_added::<I, P>)
+            .add_observer(spatial_entity_removed::<I>)
+            .add_observer(spatial_extent_removed::<I>)
+            .add_observer(spatial_layers_removed::<I>)
+            .add_systems(
+                FixedLast,
+                (
+                    spatial_position_changed::<I, P>,
+                    spatial_layers_changed::<I>,
+                )
+                    .before(filtered::SyncFilteredLookup),
+            );
     }
 }
 
@@ -233,18 +1891,30 @@ impl Plugin for SpatialQueriesPlugin {
 /// This does NOT rebuild the index every frame. It only does a full scan when:
 /// - the algorithm has never been initialized, or
 /// - a full rebuild was requested (e.g. non-incremental algorithm + entity add/remove).
-pub fn prepare_spatial_lookup(
-    all_entities: Query<(Entity, &GlobalTransform), With<SpatialQueryEntity>>,
-    mut lookup_state: ResMut<SpatialLookupState>,
+#[allow(clippy::type_complexity)]
+pub fn prepare_spatial_lookup<I: SpatialIndex, P: SpatialPosition>(
+    all_entities: Query<
+        (Entity, &P, Option<&SpatialExtent>, Option<&SpatialLayers>),
+        With<SpatialQueryEntity<I>>,
+    >,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
 ) {
     // If we haven't initialized yet, populate tracked entities from the world.
     if !lookup_state.initialized {
         lookup_state.entities.clear();
+        lookup_state.extents.clear();
+        lookup_state.layers.clear();
         lookup_state.indices.clear();
 
-        for (entity, transform) in &all_entities {
+        for (entity, position, extent, layers) in &all_entities {
             let idx = lookup_state.entities.len();
-            lookup_state.entities.push((entity, transform.translation()));
+            lookup_state.entities.push((entity, position.position()));
+            lookup_state
+                .extents
+                .push(extent.map_or(0.0, |extent| extent.0));
+            lookup_state
+                .layers
+                .push(layers.copied().unwrap_or_default());
             lookup_state.indices.insert(entity, idx);
         }
         lookup_state.request_full_rebuild();
@@ -254,35 +1924,82 @@ pub fn prepare_spatial_lookup(
 }
 
 /// Observer: when `SpatialQueryEntity` is added, incrementally insert it into the index.
-fn spatial_entity_added(
-    trigger: On<Add, SpatialQueryEntity>,
-    transforms: Query<&GlobalTransform>,
-    mut lookup_state: ResMut<SpatialLookupState>,
+fn spatial_entity_added<I: SpatialIndex, P: SpatialPosition>(
+    trigger: On<Add, SpatialQueryEntity<I>>,
+    positions: Query<(&P, Option<&SpatialExtent>, Option<&SpatialLayers>)>,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
 ) {
     let entity = trigger.entity;
-    if let Ok(gt) = transforms.get(entity) {
-        lookup_state.upsert_entity(entity, gt.translation());
+    if let Ok((position, extent, layers)) = positions.get(entity) {
+        let extent = extent.map_or(0.0, |extent| extent.0);
+        lookup_state.upsert_entity_with_extent(entity, position.position(), extent);
+        lookup_state.set_layers(entity, layers.copied().unwrap_or_default());
     }
 }
 
 /// Observer: when `SpatialQueryEntity` is removed (including despawn), remove it from the index.
-fn spatial_entity_removed(
-    trigger: On<Remove, SpatialQueryEntity>,
-    mut lookup_state: ResMut<SpatialLookupState>,
+fn spatial_entity_removed<I: SpatialIndex>(
+    trigger: On<Remove, SpatialQueryEntity<I>>,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
 ) {
     lookup_state.remove_entity(trigger.entity);
 }
 
-/// System: when an indexed entity's `GlobalTransform` changes, update its position in the index.
-fn spatial_transform_changed(
-    changed_tranforms: Query<(Entity,&GlobalTransform),(Changed<GlobalTransform>,With<SpatialQueryEntity>)>,
-    mut lookup_state: ResMut<SpatialLookupState>,
+/// Observer: when `SpatialExtent` is removed, index the entity as a point again.
+fn spatial_extent_removed<I: SpatialIndex>(
+    trigger: On<Remove, SpatialExtent>,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
 ) {
-    for (entity, gt) in changed_tranforms {
-        lookup_state.upsert_entity(entity, gt.translation());
+    let entity = trigger.entity;
+    if let Some(position) = lookup_state.position_of(entity) {
+        lookup_state.upsert_entity_with_extent(entity, position, 0.0);
     }
 }
 
-pub fn draw_spatial_lookup_gizmos(lookup_state: Res<SpatialLookupState>, mut gizmos: Gizmos) {
+/// Observer: when `SpatialLayers` is removed, move the entity back to the default layer.
+fn spatial_layers_removed<I: SpatialIndex>(
+    trigger: On<Remove, SpatialLayers>,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
+) {
+    lookup_state.set_layers(trigger.entity, SpatialLayers::DEFAULT);
+}
+
+/// System: when an indexed entity's `SpatialLayers` change, update them in the index.
+#[allow(clippy::type_complexity)]
+fn spatial_layers_changed<I: SpatialIndex>(
+    changed_layers: Query<
+        (Entity, &SpatialLayers),
+        (Changed<SpatialLayers>, With<SpatialQueryEntity<I>>),
+    >,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
+) {
+    for (entity, &layers) in changed_layers {
+        lookup_state.set_layers(entity, layers);
+    }
+}
+
+/// System: when an indexed entity's `SpatialPosition` or `SpatialExtent` changes, update its
+/// position and extent in the index.
+#[allow(clippy::type_complexity)]
+fn spatial_position_changed<I: SpatialIndex, P: SpatialPosition>(
+    changed_positions: Query<
+        (Entity, &P, Option<&SpatialExtent>),
+        (
+            Or<(Changed<P>, Changed<SpatialExtent>)>,
+            With<SpatialQueryEntity<I>>,
+        ),
+    >,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
+) {
+    for (entity, position, extent) in changed_positions {
+        let extent = extent.map_or(0.0, |extent| extent.0);
+        lookup_state.upsert_entity_with_extent(entity, position.position(), extent);
+    }
+}
+
+pub fn draw_spatial_lookup_gizmos<I: SpatialIndex>(
+    lookup_state: Res<SpatialLookupState<I>>,
+    mut gizmos: Gizmos,
+) {
     lookup_state.algorithm.debug_gizmos(&mut gizmos);
 }
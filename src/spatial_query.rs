@@ -1,40 +1,2224 @@
-use crate::SpatialLookupState;
-use crate::spatial_query_iterator::{SpatialQueryIterator, SpatialQueryIteratorRo};
-use bevy::ecs::query::{QueryData, QueryFilter, ReadOnlyQueryData};
+use crate::batch::BatchResults;
+use crate::error::SpatialQueryError;
+use crate::filtered::IndexLookup;
+use crate::spatial_query_iterator::{
+    PooledEntities, ScratchBuffers, SpatialQueryIterator, SpatialQueryIteratorRo,
+};
+use crate::{DefaultSpatialIndex, SpatialIndex, SpatialLayers};
+use bevy::camera::primitives::Frustum;
+use bevy::ecs::entity::UniqueEntityIter;
+use bevy::ecs::query::{
+    QueryData, QueryEntityError, QueryFilter, QueryParManyUniqueIter, ROQueryItem,
+    ReadOnlyQueryData,
+};
 use bevy::ecs::system::SystemParam;
-use bevy::math::Vec3;
-use bevy::prelude::{Query, Res};
+use bevy::math::{Dir3, Quat, Vec3};
+use bevy::prelude::{Entity, GlobalTransform, Local, Query};
 
+/// Lazily computed results of `nearest_iter`.
+pub type NearestResults<'a> = Box<dyn Iterator<Item = (Entity, f32)> + 'a>;
+
+/// Spatial lookups over the entities of index `I`, see `SpatialQueriesPlugin`, yielding the items
+/// of `Query<D, F>`.
 #[derive(SystemParam)]
-pub struct SpatialQuery<'w, 's, D: QueryData + 'static, F: QueryFilter + 'static = ()> {
-    lookup: Res<'w, SpatialLookupState>,
+pub struct SpatialQuery<
+    'w,
+    's,
+    D: QueryData + 'static,
+    F: QueryFilter + 'static = (),
+    I: SpatialIndex = DefaultSpatialIndex,
+> {
+    lookup: IndexLookup<'w, F, I>,
     query: Query<'w, 's, D, F>,
+    scratch: Local<'s, ScratchBuffers>,
 }
 
+/// Read-only version of `SpatialQuery`, which can be shared between systems running in parallel.
 #[derive(SystemParam)]
-pub struct ReadOnlySpatialQuery<'w, 's, D: ReadOnlyQueryData + 'static, F: QueryFilter + 'static = ()> {
-    lookup: Res<'w, SpatialLookupState>,
+pub struct ReadOnlySpatialQuery<
+    'w,
+    's,
+    D: ReadOnlyQueryData + 'static,
+    F: QueryFilter + 'static = (),
+    I: SpatialIndex = DefaultSpatialIndex,
+> {
+    lookup: IndexLookup<'w, F, I>,
     query: Query<'w, 's, D, F>,
+    scratch: Local<'s, ScratchBuffers>,
 }
 
-impl<'w, 's, D: QueryData + 'static, F: QueryFilter + 'static> SpatialQuery<'w, 's, D, F> {
+impl<'w, 's, D: QueryData + 'static, F: QueryFilter + 'static, I: SpatialIndex>
+    SpatialQuery<'w, 's, D, F, I>
+{
     pub fn in_radius<'q>(
         &'q mut self,
         sample_point: Vec3,
         radius: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, Entity, PooledEntities<'q>> {
+        self.in_radius_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_radius`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup, before any item is fetched.
+    pub fn in_radius_on_layers<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, Entity, PooledEntities<'q>> {
+        let mut entities = self.scratch.take();
+        self.lookup
+            .visit_in_radius_on_layers(sample_point, radius, mask, &mut |entity, _| {
+                entities.push(entity)
+            });
+        if self.lookup.returns_unique_entities().is_none() {
+            entities.sort_and_dedup();
+        }
+
+        // SAFETY: the algorithm promised to return each entity once, see `UniqueEntities`, or the
+        // results were just deduplicated.
+        unsafe { SpatialQueryIterator::with_unique_results(entities, &mut self.query) }
+    }
+
+    /// Returns the items of all entities in the radius of the given entity's indexed position,
+    /// excluding the entity itself.
+    ///
+    /// Returns an error if the entity is not tracked by the spatial lookup.
+    pub fn neighbors_of<'q>(
+        &'q mut self,
+        entity: Entity,
+        radius: f32,
+    ) -> Result<SpatialQueryIterator<'w, 's, 'q, D, F, Entity, PooledEntities<'q>>, SpatialQueryError>
+    {
+        self.neighbors_of_on_layers(entity, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `neighbors_of`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn neighbors_of_on_layers<'q>(
+        &'q mut self,
+        entity: Entity,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Result<SpatialQueryIterator<'w, 's, 'q, D, F, Entity, PooledEntities<'q>>, SpatialQueryError>
+    {
+        let sample_point = self
+            .lookup
+            .index()
+            .position_of(entity)
+            .ok_or(SpatialQueryError::NotIndexed(entity))?;
+
+        let mut entities = self.scratch.take();
+        self.lookup
+            .visit_in_radius_on_layers(sample_point, radius, mask, &mut |neighbor, _| {
+                if neighbor != entity {
+                    entities.push(neighbor);
+                }
+            });
+        if self.lookup.returns_unique_entities().is_none() {
+            entities.sort_and_dedup();
+        }
+
+        // SAFETY: the algorithm promised to return each entity once, see `UniqueEntities`, or the
+        // results were just deduplicated.
+        Ok(unsafe { SpatialQueryIterator::with_unique_results(entities, &mut self.query) })
+    }
+
+    /// Calls `f` with the items of every unordered pair of entities within `distance` of each
+    /// other, with mutable access to both, like `Query::get_many_mut`.
+    ///
+    /// Each pair is visited exactly once, in no particular order. Pairs where either entity
+    /// doesn't match this query are skipped.
+    pub fn for_each_pair_within(&mut self, distance: f32, f: impl FnMut([D::Item<'_, 's>; 2])) {
+        self.for_each_pair_within_on_layers(distance, SpatialLayers::ALL, f);
+    }
+
+    /// Same as `for_each_pair_within`, but only pairs up entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn for_each_pair_within_on_layers(
+        &mut self,
+        distance: f32,
+        mask: SpatialLayers,
+        mut f: impl FnMut([D::Item<'_, 's>; 2]),
+    ) {
+        let query = &mut self.query;
+        self.lookup
+            .visit_pairs_within_on_layers(distance, mask, &mut |a, b| {
+                if let Ok(items) = query.get_many_mut([a, b]) {
+                    f(items);
+                }
+            });
+    }
+
+    /// Calls `f` with every pair of an item of this query and an item of `other` whose entities
+    /// are within `distance` of each other.
+    ///
+    /// The lookup of this query is walked against the lookup of `other`, which may use a
+    /// different index or a sub-index, so this doesn't do a separate radius lookup per entity.
+    /// Entities matching both queries are never paired with themselves.
+    pub fn join_within<
+        'w2,
+        's2,
+        D2: QueryData + 'static,
+        F2: QueryFilter + 'static,
+        I2: SpatialIndex,
+    >(
+        &mut self,
+        other: &mut SpatialQuery<'w2, 's2, D2, F2, I2>,
+        distance: f32,
+        f: impl FnMut(D::Item<'_, 's>, D2::Item<'_, 's2>),
+    ) {
+        self.join_within_on_layers(other, distance, SpatialLayers::ALL, f);
+    }
+
+    /// Same as `join_within`, but only pairs up entities of either query whose layers match
+    /// `mask`, see `SpatialLayers`.
+    pub fn join_within_on_layers<
+        'w2,
+        's2,
+        D2: QueryData + 'static,
+        F2: QueryFilter + 'static,
+        I2: SpatialIndex,
+    >(
+        &mut self,
+        other: &mut SpatialQuery<'w2, 's2, D2, F2, I2>,
+        distance: f32,
+        mask: SpatialLayers,
+        mut f: impl FnMut(D::Item<'_, 's>, D2::Item<'_, 's2>),
+    ) {
+        let (left, right) = (&mut self.query, &mut other.query);
+        self.lookup
+            .visit_pairs_between_on_layers(&other.lookup, distance, mask, &mut |a, b| {
+                if a == b {
+                    return;
+                }
+                if let (Ok(l), Ok(r)) = (left.get_mut(a), right.get_mut(b)) {
+                    f(l, r);
+                }
+            });
+    }
+
+    /// Same as `in_radius`, but returns a parallel iterator which splits the matched entities
+    /// across the `ComputeTaskPool`, like `Query::par_iter_mut`.
+    ///
+    /// The batch size can be controlled with `batching_strategy`, and the results must be consumed
+    /// with `for_each`.
+    pub fn par_in_radius(
+        &mut self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> QueryParManyUniqueIter<'_, 's, D, F, Entity> {
+        self.par_in_radius_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `par_in_radius`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn par_in_radius_on_layers(
+        &mut self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> QueryParManyUniqueIter<'_, 's, D, F, Entity> {
+        let mut entities = self.scratch.take();
+        self.lookup
+            .visit_in_radius_on_layers(sample_point, radius, mask, &mut |entity, _| {
+                entities.push(entity)
+            });
+        if self.lookup.returns_unique_entities().is_none() {
+            entities.sort_and_dedup();
+        }
+
+        // SAFETY: the algorithm promised to return each entity once, see `UniqueEntities`, or the
+        // results were just deduplicated.
+        let entities = unsafe { UniqueEntityIter::from_iterator_unchecked(entities) };
+        self.query.par_iter_many_unique_mut(entities)
+    }
+
+    /// Returns the read-only query item for the given entity, like `Query::get`.
+    pub fn get(&self, entity: Entity) -> Result<ROQueryItem<'_, 's, D>, QueryEntityError> {
+        self.query.get(entity)
+    }
+
+    /// Returns the query item for the given entity, like `Query::get_mut`.
+    pub fn get_mut(&mut self, entity: Entity) -> Result<D::Item<'_, 's>, QueryEntityError> {
+        self.query.get_mut(entity)
+    }
+
+    /// Runs a radius lookup for each `(sample_point, radius)` pair, in parallel on the
+    /// `ComputeTaskPool`.
+    ///
+    /// Only entities matching this query are kept. Their items can be fetched with `get` or
+    /// `get_mut`.
+    pub fn batch_in_radius(&self, queries: &[(Vec3, f32)]) -> BatchResults {
+        self.batch_in_radius_on_layers(queries, SpatialLayers::ALL)
+    }
+
+    /// Same as `batch_in_radius`, but only keeps entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn batch_in_radius_on_layers(
+        &self,
+        queries: &[(Vec3, f32)],
+        mask: SpatialLayers,
+    ) -> BatchResults {
+        let mut results = self.lookup.batch_in_radius_on_layers(queries, mask);
+        results.retain(|entity| self.query.contains(entity));
+
+        results
+    }
+
+    /// Returns the number of indexed entities in the radius of the sample point.
+    ///
+    /// Only the spatial index is consulted, so entities which don't match this query's data and
+    /// filter are counted too, even if a `SpatialFilterPlugin` is registered for the filter.
+    pub fn count_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.count_in_radius_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `count_in_radius`, but only counts entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn count_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> usize {
+        self.lookup
+            .index()
+            .count_in_radius_on_layers(sample_point, radius, mask)
+    }
+
+    /// Same as `count_in_radius`, but approximated from the occupancy of the index instead of
+    /// testing individual entities.
+    pub fn estimate_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.lookup.index().estimate_in_radius(sample_point, radius)
+    }
+
+    /// Same as `in_radius`, but yields the items nearest first.
+    ///
+    /// The iterator can be reversed with `.rev()` to yield the items farthest first.
+    pub fn in_radius_sorted<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_radius_sorted_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_radius_sorted`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_radius_sorted_on_layers<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_radius_sorted_on_layers(sample_point, radius, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Same as `in_radius`, but yields each item together with the entity's indexed position and
+    /// its squared distance to the sample point.
+    ///
+    /// The position is the one stored in the lookup, so the entity's `GlobalTransform` does not
+    /// have to be fetched again e.g. to weight or sort the results.
+    pub fn in_radius_with_distance<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, Vec3, f32)> {
+        self.in_radius_with_distance_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_radius_with_distance`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_radius_with_distance_on_layers<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, Vec3, f32)> {
+        let entities =
+            self.lookup
+                .entities_in_radius_with_distance_on_layers(sample_point, radius, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over entities inside the axis-aligned box spanned by `min` and `max`.
+    pub fn in_aabb<'q>(
+        &'q mut self,
+        min: Vec3,
+        max: Vec3,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_aabb_on_layers(min, max, SpatialLayers::ALL)
+    }
+
+    /// Iterates over entities whose bounding sphere, see `SpatialExtent`, intersects the given
+    /// sphere. Entities without an extent are treated as points.
+    pub fn overlapping_sphere<'q>(
+        &'q mut self,
+        center: Vec3,
+        radius: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.overlapping_sphere_on_layers(center, radius, SpatialLayers::ALL)
+    }
+
+    /// Iterates over entities whose bounding sphere, see `SpatialExtent`, intersects the
+    /// axis-aligned box spanned by `min` and `max`. Entities without an extent are treated as
+    /// points.
+    pub fn overlapping_aabb<'q>(
+        &'q mut self,
+        min: Vec3,
+        max: Vec3,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.overlapping_aabb_on_layers(min, max, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_aabb`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup, before any item is fetched.
+    pub fn in_aabb_on_layers<'q>(
+        &'q mut self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities = self.lookup.entities_in_aabb_on_layers(min, max, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Same as `overlapping_sphere`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup, before any item is fetched.
+    pub fn overlapping_sphere_on_layers<'q>(
+        &'q mut self,
+        center: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_overlapping_sphere_on_layers(center, radius, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Same as `overlapping_aabb`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup, before any item is fetched.
+    pub fn overlapping_aabb_on_layers<'q>(
+        &'q mut self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_overlapping_aabb_on_layers(min, max, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over the `k` entities closest to the sample point within `max_distance`, nearest
+    /// first, yielding each item together with its distance.
+    ///
+    /// Entities not matching the query are skipped after the lookup, so fewer than `k` items may
+    /// be yielded.
+    pub fn nearest_k<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, f32)> {
+        self.nearest_k_on_layers(sample_point, k, max_distance, SpatialLayers::ALL)
+    }
+
+    /// Same as [`Self::nearest_k`], but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    ///
+    /// Unlike entities not matching the query, entities on other layers are skipped during the
+    /// lookup, so they don't take the place of the `k` nearest entities on the mask.
+    pub fn nearest_k_on_layers<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, f32)> {
+        let entities = self
+            .lookup
+            .nearest_k_on_layers(sample_point, k, max_distance, None, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Same as [`Self::nearest_k`], but never yields `exclude`, e.g. the entity doing the lookup.
+    pub fn nearest_k_excluding<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Entity,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, f32)> {
+        self.nearest_k_excluding_on_layers(
+            sample_point,
+            k,
+            max_distance,
+            exclude,
+            SpatialLayers::ALL,
+        )
+    }
+
+    /// Same as [`Self::nearest_k_on_layers`], but never yields `exclude`.
+    pub fn nearest_k_excluding_on_layers<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Entity,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, f32)> {
+        let entities =
+            self.lookup
+                .nearest_k_on_layers(sample_point, k, max_distance, Some(exclude), mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Lazily iterates over all entities nearest first, yielding each item together with its
+    /// distance, without a limit on the distance.
+    ///
+    /// The lookup only does the work needed to find the next entity, so e.g. finding the nearest
+    /// item matching some condition with `.find()` stops walking outwards once it is found.
+    pub fn nearest_iter<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, f32), NearestResults<'q>> {
+        self.nearest_iter_on_layers(sample_point, SpatialLayers::ALL)
+    }
+
+    /// Same as `nearest_iter`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup.
+    pub fn nearest_iter_on_layers<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, f32), NearestResults<'q>> {
+        let results = self.lookup.nearest_iter_on_layers(sample_point, mask);
+        SpatialQueryIterator::with_results(
+            results,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over entities within `thickness` of the segment starting at `origin` and
+    /// extending `max_t` units along `direction`, in the order they are hit.
+    ///
+    /// Each item is yielded together with its distance along the segment. Use `f32::INFINITY` as
+    /// `max_t` to query along an infinite ray.
+    pub fn along_ray<'q>(
+        &'q mut self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, f32)> {
+        self.along_ray_on_layers(origin, direction, max_t, thickness, SpatialLayers::ALL)
+    }
+
+    /// Same as `along_ray`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn along_ray_on_layers<'q>(
+        &'q mut self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F, (Entity, f32)> {
+        let entities = self
+            .lookup
+            .entities_along_ray_on_layers(origin, direction, max_t, thickness, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over entities inside the frustum.
+    ///
+    /// Camera entities carry their `Frustum` as a component, and one can also be built with
+    /// `Frustum::from_clip_from_world`, so this works without any rendering.
+    pub fn in_frustum<'q>(
+        &'q mut self,
+        frustum: &Frustum,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_frustum_on_layers(frustum, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_frustum`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`, e.g. the render layers of the camera.
+    pub fn in_frustum_on_layers<'q>(
+        &'q mut self,
+        frustum: &Frustum,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities = self.lookup.entities_in_frustum_on_layers(frustum, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over entities inside a view cone with its apex at `apex`, looking towards
+    /// `direction`.
+    ///
+    /// `half_angle` is the angle in radians between the view direction and the edge of the cone,
+    /// and `range` is the maximum distance from the apex.
+    pub fn in_cone<'q>(
+        &'q mut self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_cone_on_layers(apex, direction, half_angle, range, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_cone`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_cone_on_layers<'q>(
+        &'q mut self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_cone_on_layers(apex, direction, half_angle, range, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over entities within `radius` of the segment from `a` to `b`.
+    pub fn in_capsule<'q>(
+        &'q mut self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_capsule_on_layers(a, b, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_capsule`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_capsule_on_layers<'q>(
+        &'q mut self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_capsule_on_layers(a, b, radius, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over entities touched by a sphere of `radius` moving from `previous` to `current`.
+    ///
+    /// Unlike sampling `in_radius` at the current position, this does not miss entities the
+    /// sphere passed through between the two positions.
+    pub fn in_swept_sphere<'q>(
+        &'q mut self,
+        previous: &GlobalTransform,
+        current: &GlobalTransform,
+        radius: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_swept_sphere_on_layers(previous, current, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_swept_sphere`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_swept_sphere_on_layers<'q>(
+        &'q mut self,
+        previous: &GlobalTransform,
+        current: &GlobalTransform,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_capsule_on_layers(previous.translation(), current.translation(), radius, mask)
+    }
+
+    /// Iterates over entities inside the box with the given center and half extents, rotated by
+    /// `rotation` around its center.
+    pub fn in_obb<'q>(
+        &'q mut self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_obb_on_layers(center, half_extents, rotation, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_obb`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_obb_on_layers<'q>(
+        &'q mut self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_obb_on_layers(center, half_extents, rotation, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over entities inside a box with the given half extents, placed by `transform`.
+    ///
+    /// The transform's scale is applied to the half extents, so e.g. a trigger volume's
+    /// `GlobalTransform` together with the half extents of its unscaled shape can be used as is.
+    pub fn in_obb_from_transform<'q>(
+        &'q mut self,
+        transform: &GlobalTransform,
+        half_extents: Vec3,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_obb_from_transform_on_layers(transform, half_extents, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_obb_from_transform`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_obb_from_transform_on_layers<'q>(
+        &'q mut self,
+        transform: &GlobalTransform,
+        half_extents: Vec3,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let (scale, rotation, translation) = transform.to_scale_rotation_translation();
+        self.in_obb_on_layers(translation, half_extents * scale.abs(), rotation, mask)
+    }
+
+    /// Iterates over entities within `radius` of `center` on the XZ plane, ignoring height.
+    ///
+    /// If `y_range` is given, only entities with a world-space Y coordinate within the inclusive
+    /// range are yielded. Use [`Self::in_cylinder_along`] for other up axes.
+    pub fn in_cylinder<'q>(
+        &'q mut self,
+        center: Vec3,
+        radius: f32,
+        y_range: Option<(f32, f32)>,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_cylinder_on_layers(center, radius, y_range, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_cylinder`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_cylinder_on_layers<'q>(
+        &'q mut self,
+        center: Vec3,
+        radius: f32,
+        y_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_cylinder_along_on_layers(center, Dir3::Y, radius, y_range, mask)
+    }
+
+    /// Iterates over entities within `radius` of the line through `center` along `axis`.
+    ///
+    /// If `height_range` is given, only entities with `position.dot(axis)` within the inclusive
+    /// range are yielded.
+    pub fn in_cylinder_along<'q>(
+        &'q mut self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_cylinder_along_on_layers(center, axis, radius, height_range, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_cylinder_along`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_cylinder_along_on_layers<'q>(
+        &'q mut self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities =
+            self.lookup
+                .entities_in_cylinder_on_layers(center, axis, radius, height_range, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
+    }
+
+    /// Iterates over entities at least `min_radius` and at most `max_radius` away from the
+    /// center, e.g. for ring-shaped areas of effect.
+    pub fn in_shell<'q>(
+        &'q mut self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_shell_on_layers(center, min_radius, max_radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_shell`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_shell_on_layers<'q>(
+        &'q mut self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+        mask: SpatialLayers,
     ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
-        let entities = self.lookup.entities_in_radius(sample_point, radius);
-        SpatialQueryIterator::with_entities(entities, &mut self.query)
+        let entities = self
+            .lookup
+            .entities_in_shell_on_layers(center, min_radius, max_radius, mask);
+        SpatialQueryIterator::with_entities(
+            entities,
+            &mut self.query,
+            self.lookup.returns_unique_entities(),
+        )
     }
 }
 
-impl<'w, 's, D: ReadOnlyQueryData + 'static, F: QueryFilter + 'static> ReadOnlySpatialQuery<'w, 's, D, F> {
+impl<'w, 's, D: ReadOnlyQueryData + 'static, F: QueryFilter + 'static, I: SpatialIndex>
+    ReadOnlySpatialQuery<'w, 's, D, F, I>
+{
     pub fn in_radius<'q>(
         &'q self,
         sample_point: Vec3,
         radius: f32,
-    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
-        let entities = self.lookup.entities_in_radius(sample_point, radius);
-        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, Entity, PooledEntities<'q>> {
+        self.in_radius_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_radius`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup, before any item is fetched.
+    pub fn in_radius_on_layers<'q>(
+        &'q self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, Entity, PooledEntities<'q>> {
+        let mut entities = self.scratch.take();
+        self.lookup
+            .visit_in_radius_on_layers(sample_point, radius, mask, &mut |entity, _| {
+                entities.push(entity)
+            });
+        SpatialQueryIteratorRo::with_results(entities, &self.query)
+    }
+
+    /// Returns the items of all entities in the radius of the given entity's indexed position,
+    /// excluding the entity itself.
+    ///
+    /// Returns an error if the entity is not tracked by the spatial lookup.
+    pub fn neighbors_of<'q>(
+        &'q self,
+        entity: Entity,
+        radius: f32,
+    ) -> Result<
+        SpatialQueryIteratorRo<'w, 's, 'q, D, F, Entity, PooledEntities<'q>>,
+        SpatialQueryError,
+    > {
+        self.neighbors_of_on_layers(entity, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `neighbors_of`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn neighbors_of_on_layers<'q>(
+        &'q self,
+        entity: Entity,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> Result<
+        SpatialQueryIteratorRo<'w, 's, 'q, D, F, Entity, PooledEntities<'q>>,
+        SpatialQueryError,
+    > {
+        let sample_point = self
+            .lookup
+            .index()
+            .position_of(entity)
+            .ok_or(SpatialQueryError::NotIndexed(entity))?;
+
+        let mut entities = self.scratch.take();
+        self.lookup
+            .visit_in_radius_on_layers(sample_point, radius, mask, &mut |neighbor, _| {
+                if neighbor != entity {
+                    entities.push(neighbor);
+                }
+            });
+
+        Ok(SpatialQueryIteratorRo::with_results(entities, &self.query))
+    }
+
+    /// Calls `f` with the items of every unordered pair of entities within `distance` of each
+    /// other, like `Query::get_many`.
+    ///
+    /// Each pair is visited exactly once, in no particular order. Pairs where either entity
+    /// doesn't match this query are skipped.
+    pub fn for_each_pair_within(&self, distance: f32, f: impl FnMut([ROQueryItem<'_, 's, D>; 2])) {
+        self.for_each_pair_within_on_layers(distance, SpatialLayers::ALL, f);
+    }
+
+    /// Same as `for_each_pair_within`, but only pairs up entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn for_each_pair_within_on_layers(
+        &self,
+        distance: f32,
+        mask: SpatialLayers,
+        mut f: impl FnMut([ROQueryItem<'_, 's, D>; 2]),
+    ) {
+        self.lookup
+            .visit_pairs_within_on_layers(distance, mask, &mut |a, b| {
+                if let Ok(items) = self.query.get_many([a, b]) {
+                    f(items);
+                }
+            });
+    }
+
+    /// Calls `f` with every pair of an item of this query and an item of `other` whose entities
+    /// are within `distance` of each other.
+    ///
+    /// The lookup of this query is walked against the lookup of `other`, which may use a
+    /// different index or a sub-index, so this doesn't do a separate radius lookup per entity.
+    /// Entities matching both queries are never paired with themselves.
+    pub fn join_within<
+        'w2,
+        's2,
+        D2: ReadOnlyQueryData + 'static,
+        F2: QueryFilter + 'static,
+        I2: SpatialIndex,
+    >(
+        &self,
+        other: &ReadOnlySpatialQuery<'w2, 's2, D2, F2, I2>,
+        distance: f32,
+        f: impl FnMut(ROQueryItem<'_, 's, D>, ROQueryItem<'_, 's2, D2>),
+    ) {
+        self.join_within_on_layers(other, distance, SpatialLayers::ALL, f);
+    }
+
+    /// Same as `join_within`, but only pairs up entities of either query whose layers match
+    /// `mask`, see `SpatialLayers`.
+    pub fn join_within_on_layers<
+        'w2,
+        's2,
+        D2: ReadOnlyQueryData + 'static,
+        F2: QueryFilter + 'static,
+        I2: SpatialIndex,
+    >(
+        &self,
+        other: &ReadOnlySpatialQuery<'w2, 's2, D2, F2, I2>,
+        distance: f32,
+        mask: SpatialLayers,
+        mut f: impl FnMut(ROQueryItem<'_, 's, D>, ROQueryItem<'_, 's2, D2>),
+    ) {
+        self.lookup
+            .visit_pairs_between_on_layers(&other.lookup, distance, mask, &mut |a, b| {
+                if a == b {
+                    return;
+                }
+                if let (Ok(l), Ok(r)) = (self.query.get(a), other.query.get(b)) {
+                    f(l, r);
+                }
+            });
+    }
+
+    /// Same as `in_radius`, but returns a parallel iterator which splits the matched entities
+    /// across the `ComputeTaskPool`, like `Query::par_iter`.
+    ///
+    /// The batch size can be controlled with `batching_strategy`, and the results must be consumed
+    /// with `for_each`.
+    pub fn par_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> QueryParManyUniqueIter<'_, 's, D::ReadOnly, F, Entity> {
+        self.par_in_radius_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `par_in_radius`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn par_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> QueryParManyUniqueIter<'_, 's, D::ReadOnly, F, Entity> {
+        let mut entities = self.scratch.take();
+        self.lookup
+            .visit_in_radius_on_layers(sample_point, radius, mask, &mut |entity, _| {
+                entities.push(entity)
+            });
+        if self.lookup.returns_unique_entities().is_none() {
+            entities.sort_and_dedup();
+        }
+
+        // SAFETY: the algorithm promised to return each entity once, see `UniqueEntities`, or the
+        // results were just deduplicated.
+        let entities = unsafe { UniqueEntityIter::from_iterator_unchecked(entities) };
+        self.query.par_iter_many_unique(entities)
+    }
+
+    /// Returns the query item for the given entity, like `Query::get`.
+    pub fn get(&self, entity: Entity) -> Result<ROQueryItem<'_, 's, D>, QueryEntityError> {
+        self.query.get(entity)
+    }
+
+    /// Runs a radius lookup for each `(sample_point, radius)` pair, in parallel on the
+    /// `ComputeTaskPool`.
+    ///
+    /// Only entities matching this query are kept. Their items can be fetched with `get`.
+    pub fn batch_in_radius(&self, queries: &[(Vec3, f32)]) -> BatchResults {
+        self.batch_in_radius_on_layers(queries, SpatialLayers::ALL)
+    }
+
+    /// Same as `batch_in_radius`, but only keeps entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn batch_in_radius_on_layers(
+        &self,
+        queries: &[(Vec3, f32)],
+        mask: SpatialLayers,
+    ) -> BatchResults {
+        let mut results = self.lookup.batch_in_radius_on_layers(queries, mask);
+        results.retain(|entity| self.query.contains(entity));
+
+        results
+    }
+
+    /// Returns the number of indexed entities in the radius of the sample point.
+    ///
+    /// Only the spatial index is consulted, so entities which don't match this query's data and
+    /// filter are counted too, even if a `SpatialFilterPlugin` is registered for the filter.
+    pub fn count_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.count_in_radius_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `count_in_radius`, but only counts entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn count_in_radius_on_layers(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> usize {
+        self.lookup
+            .index()
+            .count_in_radius_on_layers(sample_point, radius, mask)
+    }
+
+    /// Same as `count_in_radius`, but approximated from the occupancy of the index instead of
+    /// testing individual entities.
+    pub fn estimate_in_radius(&self, sample_point: Vec3, radius: f32) -> usize {
+        self.lookup.index().estimate_in_radius(sample_point, radius)
+    }
+
+    /// Same as `in_radius`, but yields the items nearest first.
+    ///
+    /// The iterator can be reversed with `.rev()` to yield the items farthest first.
+    pub fn in_radius_sorted<'q>(
+        &'q self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_radius_sorted_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_radius_sorted`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_radius_sorted_on_layers<'q>(
+        &'q self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_radius_sorted_on_layers(sample_point, radius, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Same as `in_radius`, but yields each item together with the entity's indexed position and
+    /// its squared distance to the sample point.
+    ///
+    /// The position is the one stored in the lookup, so the entity's `GlobalTransform` does not
+    /// have to be fetched again e.g. to weight or sort the results.
+    pub fn in_radius_with_distance<'q>(
+        &'q self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, Vec3, f32)> {
+        self.in_radius_with_distance_on_layers(sample_point, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_radius_with_distance`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_radius_with_distance_on_layers<'q>(
+        &'q self,
+        sample_point: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, Vec3, f32)> {
+        let entities =
+            self.lookup
+                .entities_in_radius_with_distance_on_layers(sample_point, radius, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Iterates over entities inside the axis-aligned box spanned by `min` and `max`.
+    pub fn in_aabb<'q>(&'q self, min: Vec3, max: Vec3) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_aabb_on_layers(min, max, SpatialLayers::ALL)
+    }
+
+    /// Iterates over entities whose bounding sphere, see `SpatialExtent`, intersects the given
+    /// sphere. Entities without an extent are treated as points.
+    pub fn overlapping_sphere<'q>(
+        &'q self,
+        center: Vec3,
+        radius: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.overlapping_sphere_on_layers(center, radius, SpatialLayers::ALL)
+    }
+
+    /// Iterates over entities whose bounding sphere, see `SpatialExtent`, intersects the
+    /// axis-aligned box spanned by `min` and `max`. Entities without an extent are treated as
+    /// points.
+    pub fn overlapping_aabb<'q>(
+        &'q self,
+        min: Vec3,
+        max: Vec3,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.overlapping_aabb_on_layers(min, max, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_aabb`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup, before any item is fetched.
+    pub fn in_aabb_on_layers<'q>(
+        &'q self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self.lookup.entities_in_aabb_on_layers(min, max, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Same as `overlapping_sphere`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup, before any item is fetched.
+    pub fn overlapping_sphere_on_layers<'q>(
+        &'q self,
+        center: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_overlapping_sphere_on_layers(center, radius, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Same as `overlapping_aabb`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup, before any item is fetched.
+    pub fn overlapping_aabb_on_layers<'q>(
+        &'q self,
+        min: Vec3,
+        max: Vec3,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_overlapping_aabb_on_layers(min, max, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Iterates over the `k` entities closest to the sample point within `max_distance`, nearest
+    /// first, yielding each item together with its distance.
+    ///
+    /// Entities not matching the query are skipped after the lookup, so fewer than `k` items may
+    /// be yielded.
+    pub fn nearest_k<'q>(
+        &'q self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, f32)> {
+        self.nearest_k_on_layers(sample_point, k, max_distance, SpatialLayers::ALL)
+    }
+
+    /// Same as [`Self::nearest_k`], but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    ///
+    /// Unlike entities not matching the query, entities on other layers are skipped during the
+    /// lookup, so they don't take the place of the `k` nearest entities on the mask.
+    pub fn nearest_k_on_layers<'q>(
+        &'q self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, f32)> {
+        let entities = self
+            .lookup
+            .nearest_k_on_layers(sample_point, k, max_distance, None, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Same as [`Self::nearest_k`], but never yields `exclude`, e.g. the entity doing the lookup.
+    pub fn nearest_k_excluding<'q>(
+        &'q self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Entity,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, f32)> {
+        self.nearest_k_excluding_on_layers(
+            sample_point,
+            k,
+            max_distance,
+            exclude,
+            SpatialLayers::ALL,
+        )
+    }
+
+    /// Same as [`Self::nearest_k_on_layers`], but never yields `exclude`.
+    pub fn nearest_k_excluding_on_layers<'q>(
+        &'q self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+        exclude: Entity,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, f32)> {
+        let entities =
+            self.lookup
+                .nearest_k_on_layers(sample_point, k, max_distance, Some(exclude), mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Lazily iterates over all entities nearest first, yielding each item together with its
+    /// distance, without a limit on the distance.
+    ///
+    /// The lookup only does the work needed to find the next entity, so e.g. finding the nearest
+    /// item matching some condition with `.find()` stops walking outwards once it is found.
+    pub fn nearest_iter<'q>(
+        &'q self,
+        sample_point: Vec3,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, f32), NearestResults<'q>> {
+        self.nearest_iter_on_layers(sample_point, SpatialLayers::ALL)
+    }
+
+    /// Same as `nearest_iter`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`. Other entities are skipped during the lookup.
+    pub fn nearest_iter_on_layers<'q>(
+        &'q self,
+        sample_point: Vec3,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, f32), NearestResults<'q>> {
+        let results = self.lookup.nearest_iter_on_layers(sample_point, mask);
+        SpatialQueryIteratorRo::with_results(results, &self.query)
+    }
+
+    /// Iterates over entities within `thickness` of the segment starting at `origin` and
+    /// extending `max_t` units along `direction`, in the order they are hit.
+    ///
+    /// Each item is yielded together with its distance along the segment. Use `f32::INFINITY` as
+    /// `max_t` to query along an infinite ray.
+    pub fn along_ray<'q>(
+        &'q self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, f32)> {
+        self.along_ray_on_layers(origin, direction, max_t, thickness, SpatialLayers::ALL)
+    }
+
+    /// Same as `along_ray`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn along_ray_on_layers<'q>(
+        &'q self,
+        origin: Vec3,
+        direction: Dir3,
+        max_t: f32,
+        thickness: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F, (Entity, f32)> {
+        let entities = self
+            .lookup
+            .entities_along_ray_on_layers(origin, direction, max_t, thickness, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Iterates over entities inside the frustum.
+    ///
+    /// Camera entities carry their `Frustum` as a component, and one can also be built with
+    /// `Frustum::from_clip_from_world`, so this works without any rendering.
+    pub fn in_frustum<'q>(&'q self, frustum: &Frustum) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_frustum_on_layers(frustum, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_frustum`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`, e.g. the render layers of the camera.
+    pub fn in_frustum_on_layers<'q>(
+        &'q self,
+        frustum: &Frustum,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self.lookup.entities_in_frustum_on_layers(frustum, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Iterates over entities inside a view cone with its apex at `apex`, looking towards
+    /// `direction`.
+    ///
+    /// `half_angle` is the angle in radians between the view direction and the edge of the cone,
+    /// and `range` is the maximum distance from the apex.
+    pub fn in_cone<'q>(
+        &'q self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_cone_on_layers(apex, direction, half_angle, range, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_cone`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_cone_on_layers<'q>(
+        &'q self,
+        apex: Vec3,
+        direction: Dir3,
+        half_angle: f32,
+        range: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_cone_on_layers(apex, direction, half_angle, range, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Iterates over entities within `radius` of the segment from `a` to `b`.
+    pub fn in_capsule<'q>(
+        &'q self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_capsule_on_layers(a, b, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_capsule`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_capsule_on_layers<'q>(
+        &'q self,
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_capsule_on_layers(a, b, radius, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Iterates over entities touched by a sphere of `radius` moving from `previous` to `current`.
+    ///
+    /// Unlike sampling `in_radius` at the current position, this does not miss entities the
+    /// sphere passed through between the two positions.
+    pub fn in_swept_sphere<'q>(
+        &'q self,
+        previous: &GlobalTransform,
+        current: &GlobalTransform,
+        radius: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_swept_sphere_on_layers(previous, current, radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_swept_sphere`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_swept_sphere_on_layers<'q>(
+        &'q self,
+        previous: &GlobalTransform,
+        current: &GlobalTransform,
+        radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_capsule_on_layers(previous.translation(), current.translation(), radius, mask)
+    }
+
+    /// Iterates over entities inside the box with the given center and half extents, rotated by
+    /// `rotation` around its center.
+    pub fn in_obb<'q>(
+        &'q self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_obb_on_layers(center, half_extents, rotation, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_obb`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_obb_on_layers<'q>(
+        &'q self,
+        center: Vec3,
+        half_extents: Vec3,
+        rotation: Quat,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_obb_on_layers(center, half_extents, rotation, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Iterates over entities inside a box with the given half extents, placed by `transform`.
+    ///
+    /// The transform's scale is applied to the half extents, so e.g. a trigger volume's
+    /// `GlobalTransform` together with the half extents of its unscaled shape can be used as is.
+    pub fn in_obb_from_transform<'q>(
+        &'q self,
+        transform: &GlobalTransform,
+        half_extents: Vec3,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_obb_from_transform_on_layers(transform, half_extents, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_obb_from_transform`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_obb_from_transform_on_layers<'q>(
+        &'q self,
+        transform: &GlobalTransform,
+        half_extents: Vec3,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let (scale, rotation, translation) = transform.to_scale_rotation_translation();
+        self.in_obb_on_layers(translation, half_extents * scale.abs(), rotation, mask)
+    }
+
+    /// Iterates over entities within `radius` of `center` on the XZ plane, ignoring height.
+    ///
+    /// If `y_range` is given, only entities with a world-space Y coordinate within the inclusive
+    /// range are yielded. Use [`Self::in_cylinder_along`] for other up axes.
+    pub fn in_cylinder<'q>(
+        &'q self,
+        center: Vec3,
+        radius: f32,
+        y_range: Option<(f32, f32)>,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_cylinder_on_layers(center, radius, y_range, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_cylinder`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_cylinder_on_layers<'q>(
+        &'q self,
+        center: Vec3,
+        radius: f32,
+        y_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_cylinder_along_on_layers(center, Dir3::Y, radius, y_range, mask)
+    }
+
+    /// Iterates over entities within `radius` of the line through `center` along `axis`.
+    ///
+    /// If `height_range` is given, only entities with `position.dot(axis)` within the inclusive
+    /// range are yielded.
+    pub fn in_cylinder_along<'q>(
+        &'q self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_cylinder_along_on_layers(center, axis, radius, height_range, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_cylinder_along`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_cylinder_along_on_layers<'q>(
+        &'q self,
+        center: Vec3,
+        axis: Dir3,
+        radius: f32,
+        height_range: Option<(f32, f32)>,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities =
+            self.lookup
+                .entities_in_cylinder_on_layers(center, axis, radius, height_range, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+
+    /// Iterates over entities at least `min_radius` and at most `max_radius` away from the
+    /// center, e.g. for ring-shaped areas of effect.
+    pub fn in_shell<'q>(
+        &'q self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        self.in_shell_on_layers(center, min_radius, max_radius, SpatialLayers::ALL)
+    }
+
+    /// Same as `in_shell`, but only yields entities whose layers match `mask`, see
+    /// `SpatialLayers`.
+    pub fn in_shell_on_layers<'q>(
+        &'q self,
+        center: Vec3,
+        min_radius: f32,
+        max_radius: f32,
+        mask: SpatialLayers,
+    ) -> SpatialQueryIteratorRo<'w, 's, 'q, D, F> {
+        let entities = self
+            .lookup
+            .entities_in_shell_on_layers(center, min_radius, max_radius, mask);
+        SpatialQueryIteratorRo::with_entities(entities, &self.query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::Naive;
+    use crate::{SpatialLookupAlgorithm, SpatialLookupState};
+    use bevy::ecs::batching::BatchingStrategy;
+    use bevy::ecs::system::SystemState;
+    use bevy::prelude::{Component, Mut, With, World};
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Component, Default)]
+    struct Visits(u32);
+
+    /// A buggy algorithm which returns every entity twice from each lookup.
+    #[derive(Default)]
+    struct Duplicating {
+        entities: Vec<(Entity, Vec3)>,
+    }
+
+    impl Duplicating {
+        fn twice<T: Copy>(&self, f: impl Fn(Entity, Vec3) -> T) -> Vec<T> {
+            self.entities
+                .iter()
+                .chain(&self.entities)
+                .map(|&(entity, position)| f(entity, position))
+                .collect()
+        }
+    }
+
+    impl SpatialLookupAlgorithm for Duplicating {
+        fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+            self.entities = entities.to_vec();
+        }
+
+        fn entities_in_radius(&self, _sample_point: Vec3, _radius: f32) -> Vec<Entity> {
+            self.twice(|entity, _| entity)
+        }
+
+        fn entities_in_radius_with_distance(
+            &self,
+            sample_point: Vec3,
+            _radius: f32,
+        ) -> Vec<(Entity, Vec3, f32)> {
+            self.twice(|entity, position| {
+                (entity, position, position.distance_squared(sample_point))
+            })
+        }
+
+        fn entities_in_aabb(&self, _min: Vec3, _max: Vec3) -> Vec<Entity> {
+            self.twice(|entity, _| entity)
+        }
+
+        fn nearest_k(
+            &self,
+            sample_point: Vec3,
+            _k: usize,
+            _max_distance: f32,
+            _exclude: Option<Entity>,
+        ) -> Vec<(Entity, f32)> {
+            self.twice(|entity, position| (entity, position.distance(sample_point)))
+        }
+
+        fn nearest_iter(&self, sample_point: Vec3) -> NearestResults<'_> {
+            Box::new(
+                self.nearest_k(sample_point, 0, f32::INFINITY, None)
+                    .into_iter(),
+            )
+        }
+
+        fn entities_along_ray(
+            &self,
+            _origin: Vec3,
+            _direction: Dir3,
+            _max_t: f32,
+            _thickness: f32,
+        ) -> Vec<(Entity, f32)> {
+            Vec::new()
+        }
+
+        fn entities_in_frustum(&self, _frustum: &Frustum) -> Vec<Entity> {
+            Vec::new()
+        }
+
+        fn entities_in_cone(
+            &self,
+            _apex: Vec3,
+            _direction: Dir3,
+            _half_angle: f32,
+            _range: f32,
+        ) -> Vec<Entity> {
+            Vec::new()
+        }
+
+        fn entities_in_capsule(&self, _a: Vec3, _b: Vec3, _radius: f32) -> Vec<Entity> {
+            Vec::new()
+        }
+
+        fn entities_in_obb(
+            &self,
+            _center: Vec3,
+            _half_extents: Vec3,
+            _rotation: Quat,
+        ) -> Vec<Entity> {
+            Vec::new()
+        }
+
+        fn entities_in_cylinder(
+            &self,
+            _center: Vec3,
+            _axis: Dir3,
+            _radius: f32,
+            _height_range: Option<(f32, f32)>,
+        ) -> Vec<Entity> {
+            Vec::new()
+        }
+
+        fn entities_in_shell(
+            &self,
+            _center: Vec3,
+            _min_radius: f32,
+            _max_radius: f32,
+        ) -> Vec<Entity> {
+            Vec::new()
+        }
+
+        fn visit_pairs_within(&self, _distance: f32, _visit: &mut dyn FnMut(Entity, Entity)) {}
+
+        fn reports_positions(&self) -> bool {
+            true
+        }
+    }
+
+    /// Mutable iteration must never hand out the same item twice, whatever the algorithm returns.
+    ///
+    /// Small enough to run under Miri: `cargo +nightly miri test duplicate_results`.
+    #[test]
+    fn test_duplicate_results_are_yielded_once() {
+        let mut world = World::new();
+        let mut lookup = SpatialLookupState::with_algorithm(Duplicating::default());
+        for i in 0..4 {
+            let entity = world.spawn(Visits::default()).id();
+            lookup.upsert_entity(entity, Vec3::splat(i as f32));
+        }
+        lookup.prepare_algorithm();
+        assert!(lookup.returns_unique_entities().is_none());
+        assert!(
+            SpatialLookupState::with_algorithm(Naive::default())
+                .returns_unique_entities()
+                .is_some()
+        );
+        world.insert_resource(lookup);
+
+        let mut state = SystemState::<SpatialQuery<&mut Visits>>::new(&mut world);
+        let mut spatial_query = state.get_mut(&mut world);
+
+        // keeps every item alive at once, so aliasing items would be caught by Miri
+        fn visit_all<'a>(items: &mut dyn Iterator<Item = Mut<'a, Visits>>) -> usize {
+            let mut items: Vec<_> = items.collect();
+            for visits in &mut items {
+                visits.0 += 1;
+            }
+            items.len()
+        }
+
+        assert_eq!(visit_all(&mut spatial_query.in_radius(Vec3::ZERO, 10.0)), 4);
+        assert_eq!(
+            visit_all(&mut spatial_query.in_aabb(Vec3::ZERO, Vec3::ONE)),
+            4
+        );
+        assert_eq!(
+            visit_all(
+                &mut spatial_query
+                    .nearest_k(Vec3::ZERO, 8, 10.0)
+                    .map(|(item, _)| item)
+            ),
+            4
+        );
+        assert_eq!(
+            visit_all(&mut spatial_query.nearest_iter(Vec3::ZERO).map(|(item, _)| item)),
+            4
+        );
+
+        state.apply(&mut world);
+        let mut visits = world.query::<&Visits>();
+        assert!(visits.iter(&world).all(|visits| visits.0 == 4));
+    }
+
+    #[test]
+    fn test_par_in_radius() {
+        ComputeTaskPool::get_or_init(TaskPool::default);
+
+        let mut world = World::new();
+        let mut lookup = SpatialLookupState::with_algorithm(Duplicating::default());
+        for i in 0..100 {
+            let entity = world.spawn(Visits::default()).id();
+            lookup.upsert_entity(entity, Vec3::splat(i as f32));
+        }
+        lookup.prepare_algorithm();
+        world.insert_resource(lookup);
+
+        let mut state = SystemState::<SpatialQuery<&mut Visits>>::new(&mut world);
+        let mut spatial_query = state.get_mut(&mut world);
+        spatial_query
+            .par_in_radius(Vec3::ZERO, 10.0)
+            .batching_strategy(BatchingStrategy::fixed(8))
+            .for_each(|mut visits| visits.0 += 1);
+        state.apply(&mut world);
+
+        let mut state = SystemState::<ReadOnlySpatialQuery<&Visits>>::new(&mut world);
+        let spatial_query = state.get(&world);
+        let visited = AtomicUsize::new(0);
+        spatial_query
+            .par_in_radius(Vec3::ZERO, 10.0)
+            .for_each(|visits| {
+                assert_eq!(visits.0, 1);
+                visited.fetch_add(1, Ordering::Relaxed);
+            });
+
+        assert_eq!(visited.into_inner(), 100);
+    }
+
+    #[test]
+    fn test_batch_in_radius_keeps_matching_entities() {
+        let mut world = World::new();
+        let mut lookup = SpatialLookupState::with_algorithm(Naive::default());
+        let mut matching = Vec::new();
+        for i in 0..10 {
+            let entity = if i % 2 == 0 {
+                let entity = world.spawn(Visits::default()).id();
+                matching.push(entity);
+                entity
+            } else {
+                world.spawn_empty().id()
+            };
+            lookup.upsert_entity(entity, Vec3::new(i as f32, 0.0, 0.0));
+        }
+        lookup.prepare_algorithm();
+        world.insert_resource(lookup);
+
+        let mut state = SystemState::<ReadOnlySpatialQuery<&Visits>>::new(&mut world);
+        let spatial_query = state.get(&world);
+        let results = spatial_query.batch_in_radius(&[(Vec3::ZERO, 4.5), (Vec3::X * 20.0, 1.0)]);
+
+        let mut near_origin = results[0].to_vec();
+        let mut expected = matching[..3].to_vec();
+        near_origin.sort();
+        expected.sort();
+        assert_eq!(near_origin, expected);
+        assert!(results[1].is_empty());
+        assert_eq!(results.offsets(), [0, 3, 3]);
+    }
+
+    #[test]
+    fn test_neighbors_of() {
+        let mut world = World::new();
+        let mut lookup = SpatialLookupState::with_algorithm(Naive::default());
+        let entities: Vec<Entity> = (0..5)
+            .map(|_| world.spawn(Visits::default()).id())
+            .collect();
+        for (i, &entity) in entities.iter().enumerate() {
+            lookup.upsert_entity(entity, Vec3::new(i as f32, 0.0, 0.0));
+        }
+        lookup.prepare_algorithm();
+        world.insert_resource(lookup);
+        let not_indexed = world.spawn(Visits::default()).id();
+
+        let mut state = SystemState::<SpatialQuery<(Entity, &mut Visits)>>::new(&mut world);
+        let mut spatial_query = state.get_mut(&mut world);
+
+        let mut neighbors: Vec<Entity> = spatial_query
+            .neighbors_of(entities[2], 1.0)
+            .unwrap()
+            .map(|(entity, _)| entity)
+            .collect();
+        let mut expected = vec![entities[1], entities[3]];
+        neighbors.sort();
+        expected.sort();
+        assert_eq!(neighbors, expected);
+
+        assert_eq!(
+            spatial_query.neighbors_of(not_indexed, 1.0).err(),
+            Some(SpatialQueryError::NotIndexed(not_indexed))
+        );
+    }
+
+    #[test]
+    fn test_for_each_pair_within() {
+        let mut world = World::new();
+        let mut lookup = SpatialLookupState::with_algorithm(Naive::default());
+        let entities: Vec<Entity> = (0..4)
+            .map(|_| world.spawn(Visits::default()).id())
+            .collect();
+        for (&entity, x) in entities.iter().zip([0.0, 1.0, 2.0, 10.0]) {
+            lookup.upsert_entity(entity, Vec3::new(x, 0.0, 0.0));
+        }
+        lookup.prepare_algorithm();
+        world.insert_resource(lookup);
+
+        let mut state = SystemState::<SpatialQuery<&mut Visits>>::new(&mut world);
+        let mut spatial_query = state.get_mut(&mut world);
+        spatial_query.for_each_pair_within(1.0, |[mut a, mut b]| {
+            a.0 += 1;
+            b.0 += 1;
+        });
+        state.apply(&mut world);
+
+        let visits: Vec<u32> = entities
+            .iter()
+            .map(|&e| world.get::<Visits>(e).unwrap().0)
+            .collect();
+        assert_eq!(visits, [1, 2, 1, 0]);
+    }
+
+    #[derive(Component)]
+    struct Bullet;
+
+    #[test]
+    fn test_join_within() {
+        let mut world = World::new();
+        let mut lookup = SpatialLookupState::with_algorithm(Naive::default());
+        let bullet = world.spawn(Bullet).id();
+        let enemies: Vec<Entity> = (0..3)
+            .map(|_| world.spawn(Visits::default()).id())
+            .collect();
+        lookup.upsert_entity(bullet, Vec3::ZERO);
+        for (&enemy, x) in enemies.iter().zip([0.5, -1.0, 3.0]) {
+            lookup.upsert_entity(enemy, Vec3::new(x, 0.0, 0.0));
+        }
+        lookup.prepare_algorithm();
+        world.insert_resource(lookup);
+
+        let mut state = SystemState::<(
+            SpatialQuery<Entity, With<Bullet>>,
+            SpatialQuery<&mut Visits>,
+        )>::new(&mut world);
+        let (mut bullets, mut targets) = state.get_mut(&mut world);
+        bullets.join_within(&mut targets, 1.0, |entity, mut visits| {
+            assert_eq!(entity, bullet);
+            visits.0 += 1;
+        });
+        state.apply(&mut world);
+
+        let visits: Vec<u32> = enemies
+            .iter()
+            .map(|&e| world.get::<Visits>(e).unwrap().0)
+            .collect();
+        assert_eq!(visits, [1, 1, 0]);
+    }
+
+    #[test]
+    fn test_join_within_other_index() {
+        let mut world = World::new();
+        let mut bullets = SpatialLookupState::with_algorithm(crate::algorithms::Bvh::default());
+        let mut enemies = SpatialLookupState::<Enemies>::new(crate::algorithms::Bvh::default());
+        let bullet = world.spawn(Bullet).id();
+        bullets.upsert_entity(bullet, Vec3::ZERO);
+        let targets: Vec<Entity> = [0.5, -1.0, 3.0]
+            .into_iter()
+            .map(|x| {
+                let enemy = world.spawn(Visits::default()).id();
+                enemies.upsert_entity(enemy, Vec3::new(x, 0.0, 0.0));
+                enemy
+            })
+            .collect();
+        // only in the default index, so it must not be joined
+        let bystander = world.spawn(Visits::default()).id();
+        bullets.upsert_entity(bystander, Vec3::new(0.2, 0.0, 0.0));
+        bullets.prepare_algorithm();
+        enemies.prepare_algorithm();
+        world.insert_resource(bullets);
+        world.insert_resource(enemies);
+
+        let mut state = SystemState::<(
+            SpatialQuery<Entity, With<Bullet>>,
+            SpatialQuery<&mut Visits, (), Enemies>,
+        )>::new(&mut world);
+        let (mut bullets, mut targets_query) = state.get_mut(&mut world);
+        bullets.join_within(&mut targets_query, 1.0, |entity, mut visits| {
+            assert_eq!(entity, bullet);
+            visits.0 += 1;
+        });
+        state.apply(&mut world);
+
+        let visits: Vec<u32> = targets
+            .iter()
+            .chain([&bystander])
+            .map(|&e| world.get::<Visits>(e).unwrap().0)
+            .collect();
+        assert_eq!(visits, [1, 1, 0, 0]);
+    }
+
+    struct Enemies;
+
+    #[test]
+    fn test_independent_indices() {
+        use crate::{SpatialQueriesPlugin, SpatialQueryEntity};
+        use bevy::app::App;
+        use bevy::transform::components::Transform;
+
+        let mut app = App::new();
+        app.insert_resource(SpatialLookupState::<Enemies>::new(
+            crate::algorithms::Bvh::default(),
+        ))
+        .add_plugins((
+            SpatialQueriesPlugin,
+            SpatialQueriesPlugin::<Enemies>::default(),
+        ));
+        // both indices are still empty
+        app.update();
+
+        let world = app.world_mut();
+        let spawn_at = |world: &mut World, x: f32| {
+            let transform = Transform::from_xyz(x, 0.0, 0.0);
+            world
+                .spawn((transform, GlobalTransform::from(transform)))
+                .id()
+        };
+        let player = spawn_at(world, 0.0);
+        world.entity_mut(player).insert(SpatialQueryEntity);
+        let enemy = spawn_at(world, 0.5);
+        world
+            .entity_mut(enemy)
+            .insert(SpatialQueryEntity::<Enemies>::default());
+        let both = spawn_at(world, -0.5);
+        world
+            .entity_mut(both)
+            .insert((SpatialQueryEntity, SpatialQueryEntity::<Enemies>::default()));
+        app.update();
+
+        let world = app.world_mut();
+        let mut state = SystemState::<(
+            ReadOnlySpatialQuery<Entity>,
+            ReadOnlySpatialQuery<Entity, (), Enemies>,
+        )>::new(world);
+        let (default_index, enemies) = state.get(world);
+
+        let mut found: Vec<Entity> = default_index.in_radius(Vec3::ZERO, 1.0).collect();
+        found.sort();
+        let mut expected = vec![player, both];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let mut found: Vec<Entity> = enemies.in_radius(Vec3::ZERO, 1.0).collect();
+        found.sort();
+        let mut expected = vec![enemy, both];
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_layers() {
+        use crate::{SpatialQueriesPlugin, SpatialQueryEntity};
+        use bevy::app::App;
+        use bevy::transform::components::Transform;
+
+        let mut app = App::new();
+        app.add_plugins(SpatialQueriesPlugin);
+
+        let world = app.world_mut();
+        let spawn_on = |world: &mut World, x: f32, layers: Option<SpatialLayers>| {
+            let transform = Transform::from_xyz(x, 0.0, 0.0);
+            let mut entity = world.spawn((transform, GlobalTransform::from(transform)));
+            if let Some(layers) = layers {
+                entity.insert(layers);
+            }
+            entity.insert(SpatialQueryEntity).id()
+        };
+        let unlayered = spawn_on(world, 0.0, None);
+        let second = spawn_on(world, 0.5, Some(SpatialLayers(0b10)));
+        let both = spawn_on(world, -0.5, Some(SpatialLayers(0b11)));
+        app.update();
+
+        let world = app.world_mut();
+        let mut state = SystemState::<ReadOnlySpatialQuery<Entity>>::new(world);
+        let query = state.get(world);
+
+        let sorted = |mut found: Vec<Entity>| {
+            found.sort();
+            found
+        };
+        let on_layers = |mask| sorted(query.in_radius_on_layers(Vec3::ZERO, 1.0, mask).collect());
+        assert_eq!(
+            on_layers(SpatialLayers::DEFAULT),
+            sorted(vec![unlayered, both])
+        );
+        assert_eq!(on_layers(SpatialLayers(0b10)), sorted(vec![second, both]));
+        assert_eq!(
+            on_layers(SpatialLayers::ALL),
+            sorted(vec![unlayered, second, both])
+        );
+        assert_eq!(on_layers(SpatialLayers::NONE), vec![]);
+
+        let found =
+            query.in_aabb_on_layers(Vec3::splat(-1.0), Vec3::splat(1.0), SpatialLayers(0b10));
+        assert_eq!(sorted(found.collect()), sorted(vec![second, both]));
+
+        // entities on other layers don't take the place of the nearest ones on the mask
+        let sample_point = Vec3::new(0.1, 0.0, 0.0);
+        let nearest = |found: Vec<(Entity, f32)>| -> Vec<Entity> {
+            found.into_iter().map(|(entity, _)| entity).collect()
+        };
+        let found = query.nearest_k_on_layers(sample_point, 2, 1.0, SpatialLayers(0b10));
+        assert_eq!(nearest(found.collect()), vec![second, both]);
+        let found = query.nearest_k(sample_point, 2, 1.0);
+        assert_eq!(nearest(found.collect()), vec![unlayered, second]);
+        let found = query.nearest_iter_on_layers(sample_point, SpatialLayers::DEFAULT);
+        assert_eq!(nearest(found.collect()), vec![unlayered, both]);
+
+        world.entity_mut(both).remove::<SpatialLayers>();
+        let lookup = world.resource::<SpatialLookupState>();
+        assert_eq!(lookup.layers_of(both), Some(SpatialLayers::DEFAULT));
+    }
+
+    #[test]
+    fn test_filtered_sub_index() {
+        use crate::filtered::{FilteredLookupState, SpatialFilterPlugin};
+        use crate::{SpatialQueriesPlugin, SpatialQueryEntity};
+        use bevy::app::App;
+        use bevy::transform::components::Transform;
+
+        let mut app = App::new();
+        app.add_plugins((
+            SpatialQueriesPlugin,
+            SpatialFilterPlugin::<With<Bullet>>::default(),
+        ));
+
+        let world = app.world_mut();
+        let spawn_at = |world: &mut World, x: f32| {
+            let transform = Transform::from_xyz(x, 0.0, 0.0);
+            world
+                .spawn((
+                    transform,
+                    GlobalTransform::from(transform),
+                    SpatialQueryEntity,
+                ))
+                .id()
+        };
+        let mut bullets: Vec<Entity> = (0..3).map(|i| spawn_at(world, i as f32 * 0.1)).collect();
+        bullets.sort();
+        for &bullet in &bullets {
+            world.entity_mut(bullet).insert(Bullet);
+        }
+        let other = spawn_at(world, 0.0);
+        app.update();
+
+        let world = app.world_mut();
+        let mut state = SystemState::<(
+            ReadOnlySpatialQuery<Entity, With<Bullet>>,
+            ReadOnlySpatialQuery<Entity>,
+        )>::new(world);
+        let (filtered, unfiltered) = state.get(world);
+        // counts consult the whole index, whether or not there is a sub-index for the filter
+        assert_eq!(filtered.count_in_radius(Vec3::ZERO, 1.0), 4);
+        assert_eq!(unfiltered.count_in_radius(Vec3::ZERO, 1.0), 4);
+        assert_eq!(
+            filtered.estimate_in_radius(Vec3::ZERO, 1.0),
+            unfiltered.estimate_in_radius(Vec3::ZERO, 1.0)
+        );
+
+        let mut found: Vec<Entity> = filtered.in_radius(Vec3::ZERO, 1.0).collect();
+        found.sort();
+        assert_eq!(found, bullets);
+        assert_eq!(filtered.neighbors_of(other, 1.0).unwrap().count(), 3);
+
+        // sync the changes of spawning first, so only the observers can pick these up
+        app.update();
+        let world = app.world_mut();
+        world.entity_mut(bullets[0]).remove::<Bullet>();
+        world.entity_mut(other).insert(Bullet);
+        app.update();
+
+        let filtered = app.world().resource::<FilteredLookupState<With<Bullet>>>();
+        assert_eq!(filtered.position_of(bullets[0]), None);
+        assert_eq!(filtered.position_of(other), Some(Vec3::ZERO));
+        assert_eq!(filtered.entities.len(), 3);
+
+        // moves and despawns reach the sub-index through the changes of the whole index
+        let world = app.world_mut();
+        world
+            .resource_mut::<SpatialLookupState>()
+            .upsert_entity(bullets[1], Vec3::X);
+        world.despawn(bullets[2]);
+        app.update();
+
+        let filtered = app.world().resource::<FilteredLookupState<With<Bullet>>>();
+        assert_eq!(filtered.position_of(bullets[1]), Some(Vec3::X));
+        assert_eq!(filtered.position_of(bullets[2]), None);
+        assert_eq!(filtered.entities.len(), 2);
+    }
+
+    #[test]
+    fn test_filtered_sub_index_same_frame() {
+        use crate::algorithms::Octree;
+        use crate::filtered::{FilteredLookupState, SpatialFilterPlugin};
+        use crate::{SpatialQueriesPlugin, SpatialQueryEntity};
+        use bevy::app::{App, FixedLast};
+        use bevy::transform::components::Transform;
+
+        // incremental algorithms, so changes reach the indices without `PrepareSpatialLookup`
+        let mut app = App::new();
+        app.insert_resource(SpatialLookupState::with_algorithm(Octree::default()))
+            .insert_resource(FilteredLookupState::<With<Bullet>>::new(Octree::default()))
+            .add_plugins((
+                SpatialQueriesPlugin,
+                SpatialFilterPlugin::<With<Bullet>>::default(),
+            ));
+
+        let transform = Transform::default();
+        let target = app
+            .world_mut()
+            .spawn((
+                transform,
+                GlobalTransform::from(transform),
+                SpatialQueryEntity,
+            ))
+            .id();
+        app.update();
+
+        let bullets_at = |world: &mut World, center: Vec3| -> Vec<Entity> {
+            let mut state = SystemState::<ReadOnlySpatialQuery<Entity, With<Bullet>>>::new(world);
+            state.get(world).in_radius(center, 0.5).collect()
+        };
+
+        let world = app.world_mut();
+        world.entity_mut(target).insert(Bullet);
+        assert_eq!(bullets_at(world, Vec3::ZERO), vec![target]);
+
+        world.entity_mut(target).remove::<Bullet>();
+        assert_eq!(bullets_at(world, Vec3::ZERO), vec![]);
+
+        let spawned = world
+            .spawn((
+                transform,
+                GlobalTransform::from(transform),
+                Bullet,
+                SpatialQueryEntity,
+            ))
+            .id();
+        assert_eq!(bullets_at(world, Vec3::ZERO), vec![spawned]);
+
+        // moves reach the sub-index along with the whole index in `FixedLast`
+        *world.get_mut::<GlobalTransform>(spawned).unwrap() =
+            GlobalTransform::from_translation(Vec3::X);
+        world.run_schedule(FixedLast);
+        assert_eq!(bullets_at(world, Vec3::ZERO), vec![]);
+        assert_eq!(bullets_at(world, Vec3::X), vec![spawned]);
+
+        world.despawn(spawned);
+        assert_eq!(bullets_at(world, Vec3::X), vec![]);
+        let filtered = world.resource::<FilteredLookupState<With<Bullet>>>();
+        assert!(filtered.entities.is_empty());
+    }
+
+    #[derive(Component)]
+    struct Position(Vec3);
+
+    impl crate::SpatialPosition for Position {
+        fn position(&self) -> Vec3 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_custom_position_source() {
+        use crate::{SpatialQueriesPlugin, SpatialQueryEntity};
+        use bevy::app::{App, FixedLast};
+
+        let mut app = App::new();
+        app.add_plugins(SpatialQueriesPlugin::<DefaultSpatialIndex, Position>::default());
+
+        let world = app.world_mut();
+        let near = world.spawn((Position(Vec3::X), SpatialQueryEntity)).id();
+        let far = world
+            .spawn((Position(Vec3::splat(10.0)), SpatialQueryEntity))
+            .id();
+        app.update();
+
+        let lookup = app.world().resource::<SpatialLookupState>();
+        assert_eq!(lookup.position_of(near), Some(Vec3::X));
+        assert_eq!(lookup.position_of(far), Some(Vec3::splat(10.0)));
+
+        app.world_mut().get_mut::<Position>(far).unwrap().0 = Vec3::Y;
+        app.world_mut().run_schedule(FixedLast);
+        app.update();
+
+        let world = app.world_mut();
+        let mut state = SystemState::<ReadOnlySpatialQuery<Entity>>::new(world);
+        let mut found: Vec<Entity> = state.get(world).in_radius(Vec3::ZERO, 2.0).collect();
+        found.sort();
+        let mut expected = vec![near, far];
+        expected.sort();
+        assert_eq!(found, expected);
     }
 }
@@ -1,32 +1,172 @@
+use crate::UniqueEntities;
+use bevy::ecs::entity::EntityHashSet;
 use bevy::ecs::query::{QueryData, QueryFilter, ReadOnlyQueryData};
-use bevy::prelude::{Entity, Query};
+use bevy::prelude::{Entity, Query, Vec3};
+use std::sync::Mutex;
 
-pub struct SpatialQueryIterator<'w, 's, 'q, D: QueryData + 'static, F: QueryFilter + 'static> {
-    entities: Vec<Entity>,
+/// A single result produced by a spatial lookup.
+///
+/// Plain lookups only produce the matching `Entity`, and the iterators yield just the fetched
+/// query item for them. Lookups which produce extra data, like the distance to each entity, yield
+/// the fetched query item together with that data.
+pub trait SpatialQueryResult: Copy {
+    /// What the iterators yield for a fetched query item `I`.
+    type Output<I>;
+
+    /// The entity this result refers to.
+    fn entity(&self) -> Entity;
+
+    /// Combines this result with the query item fetched for its entity.
+    fn with_item<I>(self, item: I) -> Self::Output<I>;
+}
+
+impl SpatialQueryResult for Entity {
+    type Output<I> = I;
+
+    fn entity(&self) -> Entity {
+        *self
+    }
+
+    fn with_item<I>(self, item: I) -> I {
+        item
+    }
+}
+
+/// An entity and its distance to the sample point.
+impl SpatialQueryResult for (Entity, f32) {
+    type Output<I> = (I, f32);
+
+    fn entity(&self) -> Entity {
+        self.0
+    }
+
+    fn with_item<I>(self, item: I) -> (I, f32) {
+        (item, self.1)
+    }
+}
+
+/// An entity, its indexed position and its squared distance to the sample point.
+impl SpatialQueryResult for (Entity, Vec3, f32) {
+    type Output<I> = (I, Vec3, f32);
+
+    fn entity(&self) -> Entity {
+        self.0
+    }
+
+    fn with_item<I>(self, item: I) -> (I, Vec3, f32) {
+        (item, self.1, self.2)
+    }
+}
+
+/// Iterates over the query items of the entities a spatial lookup returned, with mutable access.
+///
+/// Each entity is yielded at most once, even if the lookup algorithm returned it several times,
+/// so that no two live items alias the same component data.
+pub struct SpatialQueryIterator<
+    'w,
+    's,
+    'q,
+    D: QueryData + 'static,
+    F: QueryFilter + 'static,
+    R: SpatialQueryResult = Entity,
+    I: Iterator<Item = R> = std::vec::IntoIter<R>,
+> {
+    entities: I,
     query: &'q mut Query<'w, 's, D, F>,
+    /// Entities yielded so far, or `None` if the results are known to be unique, see
+    /// `UniqueEntities`.
+    seen: Option<EntityHashSet>,
+}
+
+impl<'w, 's, 'q, D: QueryData + 'static, F: QueryFilter + 'static, R: SpatialQueryResult>
+    SpatialQueryIterator<'w, 's, 'q, D, F, R>
+{
+    pub(crate) fn with_entities(
+        entities: Vec<R>,
+        query: &'q mut Query<'w, 's, D, F>,
+        unique: Option<UniqueEntities>,
+    ) -> Self {
+        Self::with_results(entities.into_iter(), query, unique)
+    }
 }
 
-impl<'w, 's, 'q, D: QueryData + 'static, F: QueryFilter + 'static>
-    SpatialQueryIterator<'w, 's, 'q, D, F>
+impl<
+    'w,
+    's,
+    'q,
+    D: QueryData + 'static,
+    F: QueryFilter + 'static,
+    R: SpatialQueryResult,
+    I: Iterator<Item = R>,
+> SpatialQueryIterator<'w, 's, 'q, D, F, R, I>
 {
-    pub(crate) fn with_entities(entities: Vec<Entity>, query: &'q mut Query<'w, 's, D, F>) -> Self {
-        SpatialQueryIterator { entities, query }
+    /// Fetches the query items lazily, as `results` yields them.
+    ///
+    /// Entities which `results` yields more than once are skipped after their first occurrence,
+    /// unless the algorithm which produced them promised `unique` results.
+    pub(crate) fn with_results(
+        results: I,
+        query: &'q mut Query<'w, 's, D, F>,
+        unique: Option<UniqueEntities>,
+    ) -> Self {
+        SpatialQueryIterator {
+            entities: results,
+            query,
+            seen: unique.is_none().then(EntityHashSet::default),
+        }
+    }
+
+    /// Same as `with_results`, but skips tracking which entities have been yielded without a
+    /// promise of the algorithm, e.g. because `results` were just deduplicated.
+    ///
+    /// # Safety
+    ///
+    /// `results` must not yield the same entity more than once, otherwise the iterator hands out
+    /// aliasing mutable items.
+    pub(crate) unsafe fn with_unique_results(
+        results: I,
+        query: &'q mut Query<'w, 's, D, F>,
+    ) -> Self {
+        SpatialQueryIterator {
+            entities: results,
+            query,
+            seen: None,
+        }
     }
 }
 
-impl<'w, 's, 'q, D: QueryData + 'static, F: QueryFilter + 'static> Iterator
-    for SpatialQueryIterator<'w, 's, 'q, D, F>
+impl<
+    'w,
+    's,
+    'q,
+    D: QueryData + 'static,
+    F: QueryFilter + 'static,
+    R: SpatialQueryResult,
+    I: Iterator<Item = R>,
+> Iterator for SpatialQueryIterator<'w, 's, 'q, D, F, R, I>
 where
     'w: 'q,
     's: 'q,
 {
-    type Item = D::Item<'q,'q>;
+    type Item = R::Output<D::Item<'q, 'q>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(entity) = self.entities.pop() {
-            match unsafe { self.query.get_unchecked(entity) } {
+        for result in self.entities.by_ref() {
+            if self
+                .seen
+                .as_mut()
+                .is_some_and(|seen| !seen.insert(result.entity()))
+            {
+                continue;
+            }
+
+            // SAFETY: every entity is fetched at most once, see `seen`. Without it, `results` are
+            // unique, see `UniqueEntities::new` and `with_unique_results`.
+            match unsafe { self.query.get_unchecked(result.entity()) } {
                 Ok(data) => {
-                    return Some(unsafe { std::mem::transmute::<D::Item<'_,'_>, D::Item<'q,'q>>(data) });
+                    let data =
+                        unsafe { std::mem::transmute::<D::Item<'_, '_>, D::Item<'q, 'q>>(data) };
+                    return Some(result.with_item(data));
                 }
                 Err(_) => continue,
             }
@@ -36,39 +176,113 @@ where
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (0, Some(self.entities.len()))
+        (0, self.entities.size_hint().1)
     }
 }
 
-pub struct SpatialQueryIteratorRo<'w, 's, 'q, D: ReadOnlyQueryData + 'static, F: QueryFilter + 'static> {
-    entities: Vec<Entity>,
+impl<
+    'w,
+    's,
+    'q,
+    D: QueryData + 'static,
+    F: QueryFilter + 'static,
+    R: SpatialQueryResult,
+    I: DoubleEndedIterator<Item = R>,
+> DoubleEndedIterator for SpatialQueryIterator<'w, 's, 'q, D, F, R, I>
+where
+    'w: 'q,
+    's: 'q,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(result) = self.entities.next_back() {
+            if self
+                .seen
+                .as_mut()
+                .is_some_and(|seen| !seen.insert(result.entity()))
+            {
+                continue;
+            }
+
+            // SAFETY: every entity is fetched at most once, see `seen` and `next`. `next` and
+            // `next_back` consume the same iterator, so they never yield the same result twice.
+            match unsafe { self.query.get_unchecked(result.entity()) } {
+                Ok(data) => {
+                    // SAFETY: the item borrows `query` for `'q`, and no other item aliases it.
+                    let data =
+                        unsafe { std::mem::transmute::<D::Item<'_, '_>, D::Item<'q, 'q>>(data) };
+                    return Some(result.with_item(data));
+                }
+                Err(_) => continue,
+            }
+        }
+
+        None
+    }
+}
+
+pub struct SpatialQueryIteratorRo<
+    'w,
+    's,
+    'q,
+    D: ReadOnlyQueryData + 'static,
+    F: QueryFilter + 'static,
+    R: SpatialQueryResult = Entity,
+    I: Iterator<Item = R> = std::vec::IntoIter<R>,
+> {
+    entities: I,
     query: &'q Query<'w, 's, D, F>,
 }
 
-impl<'w, 's, 'q, D: ReadOnlyQueryData + 'static, F: QueryFilter + 'static>
-    SpatialQueryIteratorRo<'w, 's, 'q, D, F>
+impl<'w, 's, 'q, D: ReadOnlyQueryData + 'static, F: QueryFilter + 'static, R: SpatialQueryResult>
+    SpatialQueryIteratorRo<'w, 's, 'q, D, F, R>
 {
-    pub(crate) fn with_entities(entities: Vec<Entity>, query: &'q Query<'w, 's, D, F>) -> Self {
-        Self { entities, query }
+    pub(crate) fn with_entities(entities: Vec<R>, query: &'q Query<'w, 's, D, F>) -> Self {
+        Self::with_results(entities.into_iter(), query)
     }
 }
 
-impl<'w, 's, 'q, D: ReadOnlyQueryData + 'static, F: QueryFilter + 'static> Iterator
-    for SpatialQueryIteratorRo<'w, 's, 'q, D, F>
+impl<
+    'w,
+    's,
+    'q,
+    D: ReadOnlyQueryData + 'static,
+    F: QueryFilter + 'static,
+    R: SpatialQueryResult,
+    I: Iterator<Item = R>,
+> SpatialQueryIteratorRo<'w, 's, 'q, D, F, R, I>
+{
+    /// Fetches the query items lazily, as `results` yields them.
+    pub(crate) fn with_results(results: I, query: &'q Query<'w, 's, D, F>) -> Self {
+        Self {
+            entities: results,
+            query,
+        }
+    }
+}
+
+impl<
+    'w,
+    's,
+    'q,
+    D: ReadOnlyQueryData + 'static,
+    F: QueryFilter + 'static,
+    R: SpatialQueryResult,
+    I: Iterator<Item = R>,
+> Iterator for SpatialQueryIteratorRo<'w, 's, 'q, D, F, R, I>
 where
     'w: 'q,
     's: 'q,
 {
-    type Item = D::Item<'q, 'q>;
+    type Item = R::Output<D::Item<'q, 'q>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(entity) = self.entities.pop() {
-            match unsafe { self.query.get_unchecked(entity) } {
+        for result in self.entities.by_ref() {
+            match unsafe { self.query.get_unchecked(result.entity()) } {
                 Ok(data) => {
                     // Same reason as your mutable iterator: get_unchecked returns a shorter borrow.
-                    return Some(unsafe {
-                        std::mem::transmute::<D::Item<'_, '_>, D::Item<'q, 'q>>(data)
-                    });
+                    let data =
+                        unsafe { std::mem::transmute::<D::Item<'_, '_>, D::Item<'q, 'q>>(data) };
+                    return Some(result.with_item(data));
                 }
                 Err(_) => continue,
             }
@@ -76,3 +290,125 @@ where
         None
     }
 }
+
+impl<
+    'w,
+    's,
+    'q,
+    D: ReadOnlyQueryData + 'static,
+    F: QueryFilter + 'static,
+    R: SpatialQueryResult,
+    I: DoubleEndedIterator<Item = R>,
+> DoubleEndedIterator for SpatialQueryIteratorRo<'w, 's, 'q, D, F, R, I>
+where
+    'w: 'q,
+    's: 'q,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(result) = self.entities.next_back() {
+            match unsafe { self.query.get_unchecked(result.entity()) } {
+                Ok(data) => {
+                    let data =
+                        unsafe { std::mem::transmute::<D::Item<'_, '_>, D::Item<'q, 'q>>(data) };
+                    return Some(result.with_item(data));
+                }
+                Err(_) => continue,
+            }
+        }
+        None
+    }
+}
+
+/// Pool of entity buffers which spatial queries fill instead of allocating a fresh `Vec` per
+/// lookup.
+///
+/// Kept in the `SystemParam` state of `SpatialQuery` and `ReadOnlySpatialQuery`, so each system
+/// reuses its own buffers across runs.
+#[derive(Default)]
+pub struct ScratchBuffers(Mutex<Vec<Vec<Entity>>>);
+
+impl ScratchBuffers {
+    /// Takes a cleared buffer out of the pool, allocating only if the pool is empty.
+    pub(crate) fn take(&self) -> PooledEntities<'_> {
+        let buffer = self
+            .0
+            .lock()
+            .ok()
+            .and_then(|mut pool| pool.pop())
+            .unwrap_or_default();
+
+        PooledEntities {
+            buffer,
+            front: 0,
+            back: 0,
+            pool: self,
+        }
+    }
+}
+
+/// Entities collected into a buffer borrowed from `ScratchBuffers`.
+///
+/// The buffer is handed back to its pool when this is dropped.
+pub struct PooledEntities<'a> {
+    buffer: Vec<Entity>,
+    front: usize,
+    back: usize,
+    pool: &'a ScratchBuffers,
+}
+
+impl PooledEntities<'_> {
+    pub(crate) fn push(&mut self, entity: Entity) {
+        self.buffer.push(entity);
+        self.back = self.buffer.len();
+    }
+
+    /// Sorts the collected entities and removes duplicates, without allocating.
+    pub(crate) fn sort_and_dedup(&mut self) {
+        self.buffer.sort_unstable();
+        self.buffer.dedup();
+        self.front = 0;
+        self.back = self.buffer.len();
+    }
+}
+
+impl Iterator for PooledEntities<'_> {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        if self.front == self.back {
+            return None;
+        }
+
+        self.front += 1;
+        Some(self.buffer[self.front - 1])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.back - self.front;
+        (remaining, Some(remaining))
+    }
+}
+
+impl DoubleEndedIterator for PooledEntities<'_> {
+    fn next_back(&mut self) -> Option<Entity> {
+        if self.front == self.back {
+            return None;
+        }
+
+        self.back -= 1;
+        Some(self.buffer[self.back])
+    }
+}
+
+impl ExactSizeIterator for PooledEntities<'_> {}
+
+impl Drop for PooledEntities<'_> {
+    fn drop(&mut self) {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+
+        if let Ok(mut pool) = self.pool.0.lock() {
+            pool.push(buffer);
+        }
+    }
+}